@@ -0,0 +1,539 @@
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::fs::{File, FileTimes};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, io, process};
+
+use crate::exec::{
+    bootloader, check_requirements, parse_duration, prepare_base, RedoxerExecConfig, BASE_TOML,
+    GUI_TOML,
+};
+use crate::toolchain::{toolchain_inner, DEFAULT_TOOLCHAIN_SOURCE};
+use crate::{redoxer_dir, target};
+
+// prune removes what was not used for this long
+const PRUNE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Bootloader,
+    Image,
+    Toolchain,
+    // interrupted builds and install-configs without an image
+    Leftover,
+    // kept by clean and prune, like the vm and prepared archives
+    Other,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Bootloader => "bootloader",
+            Self::Image => "image",
+            Self::Toolchain => "toolchain",
+            Self::Leftover => "leftover",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// A cached file or folder, or the files of an image
+struct Entry {
+    kind: Kind,
+    // kind selecting the entry, a leftover goes with what left it
+    owner: Kind,
+    name: String,
+    paths: Vec<PathBuf>,
+    // on disk, images are sparse
+    size: u64,
+    built: Option<SystemTime>,
+    used: Option<SystemTime>,
+    // install-config an image came from
+    config: Option<String>,
+    // base or gui image built from another install-config, rebuilt by the next exec
+    outdated: bool,
+}
+
+impl Entry {
+    fn new(kind: Kind, name: String, paths: Vec<PathBuf>) -> io::Result<Self> {
+        let mut entry = Entry {
+            kind,
+            owner: kind,
+            name,
+            paths: Vec::new(),
+            size: 0,
+            built: None,
+            used: None,
+            config: None,
+            outdated: false,
+        };
+        for path in paths {
+            let (size, used) = disk_usage(&path)?;
+            entry.size += size;
+            entry.built = entry
+                .built
+                .max(fs::symlink_metadata(&path)?.modified().ok());
+            // install-configs are read by this command as well
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                entry.used = entry.used.max(used);
+            }
+            entry.paths.push(path);
+        }
+        Ok(entry)
+    }
+
+    fn details(&self) -> String {
+        let mut details = format!("built {}", age(self.built));
+        if self.used > self.built {
+            details.push_str(&format!(", used {}", age(self.used)));
+        }
+        if let Some(config) = &self.config {
+            details.push_str(&format!(", install-config {config}"));
+        }
+        details
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        for path in &self.paths {
+            remove_path(path)?;
+        }
+        Ok(())
+    }
+
+    /// Move the files aside while `build` makes new ones, they are removed
+    /// once it succeeded and put back when it failed
+    fn rebuild(&self, build: impl FnOnce() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let mut backups = Vec::new();
+        for path in &self.paths {
+            // a leftover of prune and clean until it is removed
+            let mut backup = path.clone().into_os_string();
+            backup.push(".refresh.partial");
+            let backup = PathBuf::from(backup);
+            if fs::symlink_metadata(&backup).is_ok() {
+                remove_path(&backup)?;
+            }
+            fs::rename(path, &backup)
+                .with_context(|| format!("unable to move {} aside", path.display()))?;
+            backups.push((path, backup));
+        }
+        match build() {
+            Ok(()) => {
+                for (_, backup) in backups {
+                    remove_path(&backup)?;
+                }
+                Ok(())
+            }
+            Err(err) => {
+                eprintln!(
+                    "redoxer: keeping the previous {} {}",
+                    self.kind.name(),
+                    self.name
+                );
+                for (path, backup) in backups {
+                    if fs::symlink_metadata(path).is_ok() {
+                        remove_path(path)?;
+                    }
+                    fs::rename(&backup, path)?;
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Record the use of a cached file in its access time, relatime and noatime
+/// mounts do not update it on every read
+pub(crate) fn mark_used(path: &Path) {
+    let times = FileTimes::new().set_accessed(SystemTime::now());
+    // only read by prune, not worth failing the run for
+    let _ = File::open(path).and_then(|file| file.set_times(times));
+}
+
+/// Kinds selected by --bootloader, --images and --toolchain, all of them when none is given
+#[derive(Default)]
+struct Selection {
+    bootloader: bool,
+    images: bool,
+    toolchain: bool,
+}
+
+impl Selection {
+    fn all(&self) -> bool {
+        !self.bootloader && !self.images && !self.toolchain
+    }
+
+    fn contains(&self, kind: Kind) -> bool {
+        match kind {
+            Kind::Bootloader => self.bootloader || self.all(),
+            Kind::Image => self.images || self.all(),
+            Kind::Toolchain => self.toolchain || self.all(),
+            // leftovers of nothing redoxer knows
+            Kind::Leftover => self.all(),
+            Kind::Other => false,
+        }
+    }
+}
+
+/// kind of the file a leftover was left by, `Kind::Leftover` when it is unknown
+fn leftover_owner(name: &str) -> Kind {
+    let name = name
+        .strip_suffix(".refresh.partial")
+        .or_else(|| name.strip_suffix(".partial"))
+        .unwrap_or(name);
+    match name {
+        "bootloader" | "bootloader.bin" => Kind::Bootloader,
+        "toolchain" => Kind::Toolchain,
+        _ if name.ends_with(".bin") || name.ends_with(".toml") => Kind::Image,
+        _ => Kind::Leftover,
+    }
+}
+
+/// size of a file or folder on disk, without following symlinks, and the
+/// last time a file in it was read, folders are read by this command as well
+fn disk_usage(path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut size = metadata.blocks() * 512;
+    let mut used = None;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let (entry_size, entry_used) = disk_usage(&entry?.path())?;
+            size += entry_size;
+            used = used.max(entry_used);
+        }
+    } else {
+        used = metadata.accessed().ok();
+    }
+    Ok((size, used))
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+fn age(time: Option<SystemTime>) -> String {
+    let Some(elapsed) = time.and_then(|time| time.elapsed().ok()) else {
+        return "at an unknown time".to_string();
+    };
+    let secs = elapsed.as_secs();
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// `~/.redoxer/$TARGET` of every target, or of `only`
+fn target_dirs(only: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+    let root = dirs::home_dir()
+        .unwrap_or(PathBuf::from("."))
+        .join(".redoxer");
+    if let Some(target) = only {
+        return Ok(vec![root.join(target)]);
+    }
+    let mut dirs = Vec::new();
+    match fs::read_dir(&root) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("unable to read {}", root.display())),
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// the bootloader, images, toolchain and leftovers of a target
+fn scan(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut names = Vec::new();
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries {
+                names.push(entry?.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    }
+    names.sort();
+
+    // an image is its .bin and its install-config, a prepared .tar it was
+    // built from is not cached by redoxer and is left alone
+    let mut images: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for name in names.iter().filter(|name| *name != "bootloader.bin") {
+        if let Some(stem) = [".bin", ".toml"]
+            .iter()
+            .find_map(|extension| name.strip_suffix(extension))
+        {
+            images
+                .entry(stem.to_string())
+                .or_default()
+                .push(dir.join(name));
+        }
+    }
+
+    let mut entries = Vec::new();
+    for name in names.iter() {
+        let path = dir.join(name);
+        let (kind, owner) = match name.as_str() {
+            "bootloader.bin" => (Kind::Bootloader, Kind::Bootloader),
+            "toolchain" => (Kind::Toolchain, Kind::Toolchain),
+            "vm" => (Kind::Other, Kind::Other),
+            // gdb scripts of older versions, written to a per-run dir now
+            "gdb" => (Kind::Leftover, Kind::Leftover),
+            // install folders of the bootloader and images, and interrupted builds
+            "bootloader" => (Kind::Leftover, Kind::Bootloader),
+            _ if name.ends_with(".partial") => (Kind::Leftover, leftover_owner(name)),
+            _ if images.contains_key(name) && path.is_dir() => (Kind::Leftover, Kind::Image),
+            _ if images.values().flatten().any(|image| *image == path) => continue,
+            _ => (Kind::Other, Kind::Other),
+        };
+        let mut entry = Entry::new(kind, name.clone(), vec![path])?;
+        entry.owner = owner;
+        entries.push(entry);
+    }
+
+    for (stem, paths) in images {
+        let toml = dir.join(format!("{stem}.toml"));
+        if paths.iter().all(|path| *path == toml) {
+            let mut entry = Entry::new(Kind::Leftover, format!("{stem}.toml"), paths)?;
+            entry.owner = Kind::Image;
+            entries.push(entry);
+            continue;
+        }
+        let builtin = match stem.as_str() {
+            "base" => Some(BASE_TOML),
+            "gui" => Some(GUI_TOML),
+            _ => None,
+        };
+        let mut entry = Entry::new(Kind::Image, stem, paths)?;
+        let config = match (fs::read_to_string(&toml), builtin) {
+            (Err(_), _) => "unknown".to_string(),
+            (Ok(config_toml), Some(builtin)) if config_toml != builtin => {
+                entry.outdated = true;
+                format!("{} (built in, outdated)", entry.name)
+            }
+            (Ok(_), Some(_)) => format!("{} (built in)", entry.name),
+            (Ok(_), None) => format!("{}.toml", entry.name),
+        };
+        entry.config = Some(config);
+        entries.push(entry);
+    }
+
+    entries.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+    Ok(entries)
+}
+
+fn list(only: Option<&str>) -> anyhow::Result<()> {
+    let mut total = 0;
+    for dir in target_dirs(only)? {
+        let entries = scan(&dir).with_context(|| format!("unable to read {}", dir.display()))?;
+        println!("{}", dir.display());
+        if entries.is_empty() {
+            println!("  empty");
+        }
+        for entry in entries.iter() {
+            println!(
+                "  {:<10} {:<24} {:>10}  {}",
+                entry.kind.name(),
+                entry.name,
+                format_size(entry.size),
+                entry.details()
+            );
+            total += entry.size;
+        }
+    }
+    println!("total {}", format_size(total));
+    Ok(())
+}
+
+/// remove the selected entries of every target, and the ones `prune` accepts
+fn remove(
+    only: Option<&str>,
+    selection: &Selection,
+    prune: impl Fn(&Entry) -> bool,
+) -> anyhow::Result<()> {
+    let mut freed = 0;
+    for dir in target_dirs(only)? {
+        let entries = scan(&dir).with_context(|| format!("unable to read {}", dir.display()))?;
+        for entry in entries
+            .iter()
+            .filter(|entry| selection.contains(entry.owner) && prune(entry))
+        {
+            eprintln!(
+                "redoxer: removing {} {} ({})",
+                entry.kind.name(),
+                dir.join(&entry.name).display(),
+                format_size(entry.size)
+            );
+            entry
+                .remove()
+                .with_context(|| format!("unable to remove {}", entry.name))?;
+            freed += entry.size;
+        }
+    }
+    eprintln!("redoxer: freed {}", format_size(freed));
+    Ok(())
+}
+
+/// rebuild the selected entries of the current target
+fn refresh(selection: &Selection) -> anyhow::Result<()> {
+    let dir = redoxer_dir();
+    let entries = scan(&dir).with_context(|| format!("unable to read {}", dir.display()))?;
+
+    let build_bootloader = || {
+        bootloader().context("unable to build bootloader")?;
+        Ok(())
+    };
+    if selection.contains(Kind::Bootloader) {
+        eprintln!("redoxer: refreshing bootloader of {}", target());
+        match entries.iter().find(|entry| entry.kind == Kind::Bootloader) {
+            Some(entry) => entry.rebuild(build_bootloader)?,
+            None => build_bootloader()?,
+        }
+    }
+
+    // images embed the bootloader, they are rebuilt with it
+    if selection.contains(Kind::Image) || selection.contains(Kind::Bootloader) {
+        let mut images = Vec::new();
+        for entry in entries.iter().filter(|entry| entry.kind == Kind::Image) {
+            let config_toml = match entry.name.as_str() {
+                "base" => BASE_TOML.to_string(),
+                "gui" => GUI_TOML.to_string(),
+                name => match fs::read_to_string(dir.join(format!("{name}.toml"))) {
+                    Ok(config_toml) => config_toml,
+                    Err(_) => {
+                        eprintln!("redoxer: skipping image {name}, its install-config is unknown");
+                        continue;
+                    }
+                },
+            };
+            images.push((entry, config_toml));
+        }
+        let mut config = RedoxerExecConfig::new(std::iter::empty())?;
+        check_requirements(&config)?;
+        if images.is_empty() {
+            config.config_toml = BASE_TOML.to_string();
+            eprintln!("redoxer: refreshing image {}", config.config_name);
+            prepare_base(&config)?;
+        }
+        for (entry, config_toml) in images {
+            eprintln!("redoxer: refreshing image {}", entry.name);
+            config.config_name.clone_from(&entry.name);
+            config.config_toml = config_toml;
+            entry.rebuild(|| {
+                prepare_base(&config)?;
+                Ok(())
+            })?;
+        }
+    }
+
+    if selection.contains(Kind::Toolchain) {
+        if env::var_os("REDOXER_TOOLCHAIN").is_some() {
+            bail!("REDOXER_TOOLCHAIN is set, the toolchain is not managed by redoxer");
+        }
+        eprintln!("redoxer: refreshing toolchain of {}", target());
+        let build_toolchain = || {
+            toolchain_inner(false, DEFAULT_TOOLCHAIN_SOURCE.to_string())
+                .context("unable to install toolchain")?;
+            Ok(())
+        };
+        match entries.iter().find(|entry| entry.kind == Kind::Toolchain) {
+            Some(entry) => entry.rebuild(build_toolchain)?,
+            None => build_toolchain()?,
+        }
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("redoxer cache list [--target target] - show cached files of every target");
+    eprintln!(
+        "redoxer cache clean [--bootloader] [--images] [--toolchain] [--target target] - remove cached files"
+    );
+    eprintln!(
+        "redoxer cache prune [--older-than duration] [--bootloader] [--images] [--toolchain] [--target target] - remove unused and outdated files"
+    );
+    eprintln!(
+        "redoxer cache refresh [--bootloader] [--images] [--toolchain] - rebuild cached files of $TARGET"
+    );
+    process::exit(1);
+}
+
+fn inner(args: &[String]) -> anyhow::Result<()> {
+    let Some(command) = args.get(2) else {
+        usage();
+    };
+    let mut selection = Selection::default();
+    let mut only = None;
+    let mut older_than = PRUNE_AGE;
+    let mut args = args.iter().skip(3);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bootloader" => selection.bootloader = true,
+            "--images" => selection.images = true,
+            "--toolchain" => selection.toolchain = true,
+            "--target" if command != "refresh" => match args.next() {
+                Some(target) => only = Some(target.as_str()),
+                None => bail!("--target requires a target"),
+            },
+            "--older-than" if command == "prune" => match args.next() {
+                Some(duration) => older_than = parse_duration(duration)?,
+                None => bail!("--older-than requires a duration"),
+            },
+            _ => usage(),
+        }
+    }
+
+    match command.as_str() {
+        "list" if selection.all() => list(only),
+        "clean" => remove(only, &selection, |_| true),
+        "prune" => remove(only, &selection, |entry| {
+            let unused = entry
+                .used
+                .max(entry.built)
+                .and_then(|time| time.elapsed().ok())
+                .is_some_and(|elapsed| elapsed > older_than);
+            entry.kind == Kind::Leftover || entry.outdated || unused
+        }),
+        "refresh" => refresh(&selection),
+        _ => usage(),
+    }
+}
+
+pub fn main(args: &[String]) {
+    match inner(args) {
+        Ok(()) => {
+            process::exit(0);
+        }
+        Err(err) => {
+            eprintln!("redoxer cache: {err:#}");
+            process::exit(1);
+        }
+    }
+}
@@ -2,7 +2,26 @@ use std::process;
 
 use crate::{status_error, target};
 
-fn inner<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<()> {
+/// Rules skipping .git and the target directory of the cargo project copied
+/// from `root_dir`, only the binaries of this target are needed from it
+#[cfg(feature = "cli-exec")]
+pub(crate) fn project_rules(root_dir: &std::path::Path) -> Vec<(bool, String)> {
+    let mut rules = Vec::new();
+    let target_dir = std::env::var("CARGO_TARGET_DIR").unwrap_or("target".to_string());
+    if let Ok(target_dir) = std::fs::canonicalize(target_dir)
+        && let Ok(target_dir) = target_dir.strip_prefix(root_dir)
+    {
+        let target_dir = target_dir.display();
+        rules.push((false, "/.git/".to_string()));
+        rules.push((false, format!("/{target_dir}/{}/*/incremental/", target())));
+        rules.push((false, format!("/{target_dir}/{}/*/.fingerprint/", target())));
+        rules.push((true, format!("/{target_dir}/{}/", target())));
+        rules.push((false, format!("/{target_dir}/*")));
+    }
+    rules
+}
+
+fn inner<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<i32> {
     let command = args.next().unwrap();
     let subcommand = args.next().unwrap();
 
@@ -14,10 +33,24 @@ fn inner<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<()> {
             .context("Unable to parse exec configuration")?;
         let arguments = runner_config.arguments.clone();
         runner_config.arguments = Vec::new();
-        runner_config.folders.insert(
-            "root".to_string(),
-            format!("{}/", fs::canonicalize(".")?.display()),
-        );
+        let root_dir = fs::canonicalize(".")?;
+        runner_config
+            .folders
+            .insert("root".to_string(), format!("{}/", root_dir.display()));
+        // cargo runs binaries from the package directory
+        runner_config.cwd_from_host = runner_config.cwd.is_none();
+        runner_config.filter.rules.extend(project_rules(&root_dir));
+
+        if subcommand != "test" && (runner_config.jobs.is_some() || runner_config.rerun_failed) {
+            anyhow::bail!("--jobs and --rerun-failed only apply to redoxer test");
+        }
+        // cargo runs the test binaries one by one, a report covers all of them
+        let jobs = runner_config.jobs.unwrap_or(1);
+        if subcommand == "test"
+            && (jobs > 1 || !runner_config.reports.is_empty() || runner_config.rerun_failed)
+        {
+            return crate::parallel::test(&runner_config, jobs, &arguments);
+        }
 
         let mut runner = vec![command, "exec".to_string()];
         runner.extend(runner_config.to_args().into_iter().map(|s| {
@@ -37,9 +70,17 @@ fn inner<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<()> {
             match (arg.as_str(), matching) {
                 (
                     "-f" | "--folder" | "-a" | "--artifact" | "-i" | "--install-config" | "-o"
-                    | "--output" | "-g" | "--gui" | "-h" | "--help",
+                    | "--output" | "-g" | "--gui" | "-h" | "--help" | "-t" | "--timeout"
+                    | "--stdin" | "-s" | "--split-output" | "-q" | "--quiet-boot" | "-e" | "--env"
+                    | "--env-pass" | "-C" | "--cwd" | "--cwd-from-host" | "--include" | "--exclude"
+                    | "--gitignore" | "--forward" | "--network" | "--gdb" | "-j" | "--jobs"
+                    | "--report" | "--retries" | "--repeat" | "--rerun-failed" | "--profile"
+                    | "--memory" | "--cpus" | "--machine" | "--timings" | "--status-file",
                     true,
                 ) => anyhow::bail!("feature 'cli-exec' is not compiled, please omit exec args"),
+                (gdb, true) if gdb.starts_with("--gdb=") => {
+                    anyhow::bail!("feature 'cli-exec' is not compiled, please omit exec args")
+                }
                 ("--", true) => matching = false,
                 _ => {
                     matching = false;
@@ -62,13 +103,13 @@ fn inner<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<()> {
         .status()
         .and_then(status_error)?;
 
-    Ok(())
+    Ok(0)
 }
 
 pub fn main(args: &[String]) {
     match inner(args.iter().cloned()) {
-        Ok(()) => {
-            process::exit(0);
+        Ok(code) => {
+            process::exit(code);
         }
         Err(err) => {
             eprintln!("redoxer cargo: {err}");
@@ -0,0 +1,142 @@
+use crate::output::clean_line;
+
+// lines shown around the line that matched
+const EXCERPT_BEFORE: usize = 3;
+const EXCERPT_AFTER: usize = 8;
+
+/// Ways the guest fails before the command can report its exit status
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum CrashKind {
+    KernelPanic,
+    CpuException,
+    Reset,
+    InitFailure,
+    DaemonPanic,
+}
+
+impl CrashKind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::KernelPanic => "kernel panic",
+            Self::CpuException => "cpu exception",
+            Self::Reset => "reset or triple fault",
+            Self::InitFailure => "init failure",
+            Self::DaemonPanic => "daemon panic",
+        }
+    }
+
+    /// exit status of redoxer, following qemu (2) and redoxer (3) failures,
+    /// a command can exit with it too, --status-file tells them apart
+    pub(crate) fn exit_code(self) -> i32 {
+        match self {
+            Self::KernelPanic => 4,
+            Self::CpuException => 5,
+            Self::Reset => 6,
+            Self::InitFailure => 7,
+            Self::DaemonPanic => 8,
+        }
+    }
+}
+
+// checked in order, the first kind found wins
+const SIGNATURES: &[(CrashKind, &[&str])] = &[
+    (CrashKind::KernelPanic, &["KERNEL PANIC", "kernel panicked"]),
+    (
+        CrashKind::CpuException,
+        &[
+            "Page fault",
+            "General protection fault",
+            "Double fault",
+            "Invalid opcode",
+            "Divide by zero",
+            "Stack segment fault",
+            "Segment not present",
+            "Alignment check",
+            "Machine check",
+            "Synchronous exception",
+            // riscv64 trap causes
+            "Illegal instruction",
+            "Instruction page fault",
+            "Load page fault",
+            "Store page fault",
+            "Load access fault",
+            "Store access fault",
+        ],
+    ),
+    (CrashKind::InitFailure, &["init: failed", "init: error"]),
+];
+
+// printed by the bootloader, seen twice when the machine was reset
+const BOOTLOADER_BANNER: &str = "Redox OS Bootloader";
+
+pub(crate) struct Crash {
+    pub(crate) kind: CrashKind,
+    pub(crate) excerpt: String,
+}
+
+impl Crash {
+    /// summary shown on failure and appended to the -o output
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "## redoxer crash: {} (exit status {}) ##\n{}## redoxer crash end ##\n",
+            self.kind.name(),
+            self.kind.exit_code(),
+            self.excerpt
+        )
+    }
+}
+
+/// Look for known panic and fault messages in the serial console, the boot
+/// part of it, and the debug log
+pub(crate) fn analyze(serial: &[u8], boot: &[u8], log: &[u8]) -> Option<Crash> {
+    let serial = lines(serial);
+    let log = lines(log);
+
+    for (kind, patterns) in SIGNATURES {
+        for lines in [&serial, &log] {
+            if let Some(i) = lines
+                .iter()
+                .position(|line| patterns.iter().any(|p| line.contains(p)))
+            {
+                return Some(crash(*kind, lines, i));
+            }
+        }
+    }
+
+    let mut banners = serial
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.contains(BOOTLOADER_BANNER));
+    if let (Some(_), Some((i, _))) = (banners.next(), banners.next()) {
+        return Some(crash(CrashKind::Reset, &serial, i));
+    }
+
+    // panics after boot come from the command itself
+    let boot = lines(boot);
+    if let Some(i) = boot.iter().position(|line| line.contains("panicked at")) {
+        return Some(crash(CrashKind::DaemonPanic, &boot, i));
+    }
+
+    None
+}
+
+fn lines(data: &[u8]) -> Vec<String> {
+    data.split_inclusive(|b| *b == b'\n')
+        .map(|line| {
+            String::from_utf8_lossy(&clean_line(line))
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+fn crash(kind: CrashKind, lines: &[String], i: usize) -> Crash {
+    let start = i.saturating_sub(EXCERPT_BEFORE);
+    let end = (i + EXCERPT_AFTER + 1).min(lines.len());
+    let mut excerpt = String::new();
+    for line in &lines[start..end] {
+        excerpt.push_str(line);
+        excerpt.push('\n');
+    }
+    Crash { kind, excerpt }
+}
@@ -1,31 +1,103 @@
 use anyhow::{bail, Context};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::{self, Command, Stdio};
-use std::{fs, io};
-
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
+
+use crate::cache::mark_used;
+use crate::crash::{self, Crash, CrashKind};
+use crate::filter::CopyFilter;
+use crate::gdb::{self, GDB_PORT};
+use crate::output::{BootConsole, ConsoleMode, GuestLog};
+use crate::profile::{parse_memory, Profile, ProfileArgs};
+use crate::qemu::{split_args, QemuArgs};
 use crate::redoxfs::{
-    archive_image, expand_disk, extract_tar, run_install_mount, run_install_to_dir, shrink_disk,
-    RedoxFs,
+    archive_image, expand_disk, extract_path, extract_tar, populate_image, run_install_mount,
+    run_install_to_dir, shrink_disk,
 };
-use crate::writer::write_redoxerd_config;
-use crate::{host_target, parse_bool_env, redoxer_dir, status_error, target};
+use crate::report::{self, Report, TestSuite};
+use crate::writer::{write_redoxer_agent, write_redoxerd_config, ExecWrapper, REPEAT_MARKER};
+use crate::{host_target, parse_bool_env, redoxer_dir, status_error, target, timings, vm};
 
 // extra disk space to fit large projects
 const DISK_SIZE: u64 = 3 * 1024 * 1024 * 1024;
-// need to fit under the default RAM
+// need to fit under the default RAM, checked by Profile::validate
 const DISK_SIZE_LIVE: u64 = 1024 * 1024 * 1024;
+// forwarded to the guest when set on the host
+const ENV_PASS_DEFAULT: &[&str] = &["RUST_BACKTRACE", "RUST_LOG", "RUST_TEST_THREADS"];
+// same as timeout(1), distinct from guest (1) and qemu (2) failures
+const EXIT_TIMEOUT: i32 = 124;
+
+/// How a run ended, the exit status alone cannot tell a command exiting
+/// with 1, 2 or 124 from redoxer reporting a failure
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum RunStatus {
+    // the command reported its exit status
+    Guest(i32),
+    // the guest stopped without reporting the exit status of the command
+    Failure,
+    // qemu exited with an unexpected status
+    Qemu,
+    // the timeout passed before the command finished
+    Timeout,
+    // the guest crashed before the command reported its exit status
+    Crash(CrashKind),
+}
+
+impl RunStatus {
+    pub(crate) fn exit_code(self) -> i32 {
+        match self {
+            Self::Guest(status) => status,
+            Self::Failure => 1,
+            Self::Qemu => 2,
+            Self::Timeout => EXIT_TIMEOUT,
+            Self::Crash(kind) => kind.exit_code(),
+        }
+    }
+
+    fn result(self) -> &'static str {
+        match self {
+            Self::Guest(_) => "guest",
+            Self::Failure => "failure",
+            Self::Qemu => "qemu",
+            Self::Timeout => "timeout",
+            Self::Crash(_) => "crash",
+        }
+    }
+
+    fn crash(self) -> Option<CrashKind> {
+        match self {
+            Self::Crash(kind) => Some(kind),
+            _ => None,
+        }
+    }
+}
+
+/// Write the --status-file, `result` is `guest` when `exit_code` is the exit
+/// status of the command, or `failure`, `qemu`, `timeout`, `crash` or `error`
+/// when it comes from redoxer, a crash adds its kind as `crash`
+fn write_status_file(
+    path: &str,
+    result: &str,
+    exit_code: i32,
+    crash: Option<CrashKind>,
+) -> io::Result<()> {
+    let mut status = format!("result={result}\nexit_code={exit_code}\n");
+    if let Some(crash) = crash {
+        status.push_str(&format!("crash={}\n", crash.name()));
+    }
+    fs::write(path, status)
+}
 
 pub fn qemu_executable() -> &'static str {
     match target() {
         "x86_64-unknown-redox" => "qemu-system-x86_64",
         "aarch64-unknown-redox" => "qemu-system-aarch64",
         "i586-unknown-redox" | "i686-unknown-redox" => "qemu-system-i386",
-        // "riscv64gc-unknown-redox" => "qemu-system-riscv64",
-        "riscv64gc-unknown-redox" => todo!(
-            "RISC-V does not have a working driver to report test result.\n\
-        If you insist to, try again with `export REDOXER_QEMU_BINARY=qemu-system-riscv64`"
-        ),
+        "riscv64gc-unknown-redox" => "qemu-system-riscv64",
         _ => panic!("Unknown target architecture for QEMU"),
     }
 }
@@ -128,13 +200,14 @@ pub fn qemu_default_args() -> Vec<&'static str> {
     default_args
 }
 
-static BASE_TOML: &str = include_str!("../res/base.toml");
-static GUI_TOML: &str = include_str!("../res/gui.toml");
+pub(crate) static BASE_TOML: &str = include_str!("../res/base.toml");
+pub(crate) static GUI_TOML: &str = include_str!("../res/gui.toml");
 
-fn bootloader() -> anyhow::Result<PathBuf> {
+pub(crate) fn bootloader() -> anyhow::Result<PathBuf> {
+    let _phase = timings::phase("bootloader");
     let bootloader_bin = redoxer_dir().join("bootloader.bin");
     if !bootloader_bin.is_file() {
-        eprintln!("redoxer: building bootloader");
+        progress!("redoxer: building bootloader");
 
         let bootloader_dir = redoxer_dir().join("bootloader");
         if bootloader_dir.is_dir() {
@@ -173,9 +246,8 @@ fn base(
     config_str: &str,
     fuse: bool,
 ) -> anyhow::Result<(PathBuf, bool)> {
-    let ext = if fuse { "bin" } else { "tar" };
-
-    let base_file = redoxer_dir().join(format!("{name}.{ext}"));
+    let _phase = timings::phase("base image");
+    let base_file = redoxer_dir().join(format!("{}.{}", name, "bin"));
     let base_tar = redoxer_dir().join(format!("{}.{}", name, "tar"));
     let base_toml = redoxer_dir().join(format!("{}.{}", name, "toml"));
 
@@ -186,13 +258,14 @@ fn base(
     if base_toml.is_file() && base_file.is_file() {
         let r = fs::read_to_string(&base_toml).context("Unable to read base toml")?;
         if r != config_str {
-            eprintln!("redoxer: clearing old {name}");
+            // a prepared base tar is an input, only the outputs are rebuilt
+            progress!("redoxer: clearing old {name}");
             fs::remove_file(&base_toml).context("Unable to delete base toml")?;
             fs::remove_file(&base_file).context("Unable to delete base bin/tar")?;
         }
     }
     if !base_file.is_file() {
-        eprintln!("redoxer: building {name}");
+        progress!("redoxer: building {name}");
 
         let base_dir = redoxer_dir().join(name);
         if base_dir.is_dir() {
@@ -200,7 +273,7 @@ fn base(
         }
         fs::create_dir_all(&base_dir)?;
 
-        let base_partial = redoxer_dir().join(format!("{name}.{ext}.partial"));
+        let base_partial = redoxer_dir().join(format!("{name}.bin.partial"));
         if base_partial.is_file() {
             fs::remove_file(&base_partial)?;
         }
@@ -217,32 +290,40 @@ fn base(
                 &base_dir,
                 &base_partial,
             )?;
-
-            // only shrink disk outside CI
-            if !base_tar.exists() {
-                eprintln!("redoxer: shrinking {name}");
-                // TODO: The bootloader unable to boot
-                if !qemu_use_uefi() {
-                    shrink_disk(&base_partial)?;
-                }
-            }
         } else {
-            run_install_to_dir(config, &base_dir)?;
+            if base_tar.exists() {
+                // redoxer in docker was built with a prepared archive
+                progress!("redoxer: extracting archive");
+                extract_tar(&base_tar, &base_dir)?;
+            } else {
+                run_install_to_dir(config, &base_dir)?;
+            }
+
+            archive_image(
+                &base_partial,
+                &base_dir,
+                bootloader_bin,
+                qemu_use_uefi(),
+                qemu_disk_size(),
+            )?;
+        }
 
-            eprintln!("redoxer: compressing {name}");
-            Command::new("tar")
-                .arg("-c")
-                .arg("-p")
-                .arg("-f")
-                .arg(&base_partial)
-                .arg("-C")
-                .arg(&base_dir)
-                .arg(".")
-                .status()
-                .and_then(status_error)?;
+        // only shrink disk outside CI
+        if !base_tar.exists() {
+            progress!("redoxer: shrinking {name}");
+            // TODO: The bootloader unable to boot
+            if !qemu_use_uefi() {
+                shrink_disk(&base_partial)?;
+            }
         }
+        // expanded once here, the space is a hole that copies of the base keep sparse
+        expand_disk(&base_partial, qemu_disk_size())?;
 
         fs::rename(&base_partial, &base_file)?;
+        // every run works on a copy, the cached base is never modified
+        let mut permissions = fs::metadata(&base_file)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&base_file, permissions)?;
         if base_dir.is_dir() {
             fs::remove_dir_all(&base_dir)?;
         }
@@ -251,41 +332,67 @@ fn base(
     Ok((base_file, has_orbital))
 }
 
-fn apply_qemu_args(cmd: &mut Command, default: Vec<&str>, args_opt: Option<Vec<&str>>) {
-    let final_args = if let Some(user_args) = args_opt {
-        let user_opts: HashSet<&str> = user_args
-            .iter()
-            .filter(|arg| arg.starts_with('-'))
-            .copied()
-            .collect();
+fn apply_qemu_args(
+    cmd: &mut Command,
+    default: Vec<&str>,
+    config: &RedoxerExecConfig,
+) -> anyhow::Result<()> {
+    let (network, forwards) = (config.network, &config.forwards);
+    let mut args = QemuArgs::parse(&default);
+    if let Some(user_args) = &config.qemu_args {
+        args.merge(QemuArgs::parse(&split_args(user_args)?));
+    }
+    if let Some(remove_args) = &config.qemu_args_remove {
+        args.remove(&QemuArgs::parse(&split_args(remove_args)?));
+    }
+    if let Some(append_args) = &config.qemu_args_append {
+        args.append(QemuArgs::parse(&split_args(append_args)?));
+    }
+    let mut final_args = args.into_args();
 
-        let mut merged_args: Vec<String> = Vec::new();
+    if network == Network::None {
+        // drop every network device, -nic none also stops qemu adding its default one
         let mut i = 0;
-        while i < default.len() {
-            let opt = &default[i];
-            if !opt.starts_with('-') {
-                continue; // shouldn't go here
-            }
-
-            let is_single_flag = default.get(i + 1).is_none_or(|a| a.starts_with('-'));
-
-            if !user_opts.contains(opt) {
-                merged_args.push(opt.to_string());
-                if !is_single_flag {
-                    merged_args.push(default[i + 1].to_string());
-                }
+        while i + 1 < final_args.len() {
+            let is_nic = match final_args[i].as_str() {
+                "-netdev" | "-nic" => true,
+                "-device" => final_args[i + 1].contains("netdev="),
+                _ => false,
+            };
+            if is_nic {
+                final_args.drain(i..i + 2);
+            } else {
+                i += 1;
             }
-
-            i += if is_single_flag { 1 } else { 2 };
         }
+        final_args.extend(["-nic".to_string(), "none".to_string()]);
+    }
 
-        merged_args.extend(user_args.into_iter().map(String::from));
-        merged_args
-    } else {
-        default.into_iter().map(String::from).collect()
-    };
+    if network == Network::Isolated || !forwards.is_empty() {
+        // forwards go to the user mode netdev, either the default or one from REDOXER_QEMU_ARGS
+        let netdev = final_args
+            .windows(2)
+            .position(|w| {
+                matches!(w[0].as_str(), "-netdev" | "-nic")
+                    && (w[1] == "user" || w[1].starts_with("user,"))
+            })
+            .map(|i| i + 1);
+        let Some(netdev) = netdev else {
+            bail!("--forward and --network isolated require a user mode network, but none was found in the qemu args");
+        };
+        if network == Network::Isolated {
+            final_args[netdev].push_str(",restrict=on");
+        }
+        for forward in forwards {
+            final_args[netdev].push_str(&format!(
+                ",hostfwd={}:127.0.0.1:{}-:{}",
+                forward.protocol, forward.host_port, forward.guest_port
+            ));
+        }
+    }
 
     cmd.args(final_args);
+    Ok(())
 }
 
 fn installed(program: &str) -> io::Result<bool> {
@@ -296,14 +403,15 @@ fn installed(program: &str) -> io::Result<bool> {
         .map(|x| x.success())
 }
 
-fn inner(config: &RedoxerExecConfig) -> anyhow::Result<i32> {
-    let qemu_binary = config
+fn qemu_binary(config: &RedoxerExecConfig) -> &str {
+    config
         .qemu_binary
         .as_deref()
-        .unwrap_or_else(|| qemu_executable());
-    // it is unusual to request custom qemu binary
-    let qemu_verbose = config.qemu_binary.is_some();
+        .unwrap_or_else(|| qemu_executable())
+}
 
+pub(crate) fn check_requirements(config: &RedoxerExecConfig) -> io::Result<()> {
+    let qemu_binary = qemu_binary(config);
     if !installed(qemu_binary)? {
         eprintln!(
             "redoxer: {} not found, please install before continuing",
@@ -311,206 +419,632 @@ fn inner(config: &RedoxerExecConfig) -> anyhow::Result<i32> {
         );
         process::exit(1);
     }
-    let kvm = qemu_has_kvm();
-
-    let fuse = config.fuse;
 
-    if fuse {
-        if !installed("fusermount")? {
-            eprintln!("redoxer: fuse not found, please install before continuing");
-            process::exit(1);
-        }
-    } else if !installed("tar")? {
-        eprintln!("redoxer: tar not found, please install before continuing");
+    // only building the base image mounts it
+    if config.fuse && !installed("fusermount")? {
+        eprintln!("redoxer: fuse not found, please install before continuing");
         process::exit(1);
     }
 
+    Ok(())
+}
+
+/// disk image populated with the folders and command of an exec configuration
+pub(crate) struct RedoxerImage {
+    pub(crate) disk: PathBuf,
+    pub(crate) dir: PathBuf,
+    pub(crate) gui: bool,
+}
+
+/// build the base image of an exec configuration if it is missing or outdated,
+/// returns its path and whether it has orbital
+pub(crate) fn prepare_base(config: &RedoxerExecConfig) -> anyhow::Result<(PathBuf, bool)> {
     let bootloader_bin = bootloader().context("unable to init bootloader")?;
-    let (base_file, gui) = base(
+    mark_used(&bootloader_bin);
+    base(
         &bootloader_bin,
         &config.config_name,
         &config.config_toml,
-        fuse,
+        config.fuse,
     )
-    .context("unable to init base")?;
+    .context("unable to init base")
+}
 
-    eprintln!("redoxer: creating temporary disk");
-    let tempdir = tempfile::tempdir().context("unable to create tempdir")?;
-    let redoxer_bin = tempdir.path().join("redoxer.bin");
-    let dest_dir = tempdir.path().join("redoxer");
+/// creating a bootable disk inside `work_dir`, with `/etc/redoxerd` pointing
+/// to the command, or to the vm agent if `vm` is set
+pub(crate) fn prepare_image(
+    config: &RedoxerExecConfig,
+    work_dir: &Path,
+    vm: bool,
+) -> anyhow::Result<RedoxerImage> {
+    let (base_file, gui) = prepare_base(config)?;
+    mark_used(&base_file);
+
+    let redoxer_bin = work_dir.join("redoxer.bin");
+    let dest_dir = work_dir.join("redoxer");
+
+    let phase = timings::phase("disk copy");
+    let mut cp = Command::new("cp");
+    if config.reflink {
+        // share unchanged blocks with the base on btrfs and xfs, a full copy of
+        // the data elsewhere, the holes are never written
+        cp.arg("--reflink=auto").arg("--sparse=always");
+    }
+    cp.arg(&base_file)
+        .arg(&redoxer_bin)
+        .status()
+        .and_then(status_error)
+        .context("copy base to redoxer bin failed")?;
+    fs::set_permissions(&redoxer_bin, fs::Permissions::from_mode(0o644))
+        .context("unable to make redoxer bin writable")?;
 
-    let code = {
-        if fuse {
-            Command::new("cp")
-                .arg(&base_file)
-                .arg(&redoxer_bin)
-                .status()
-                .and_then(status_error)
-                .context("copy base to redoxer bin failed")?;
+    // only bases cached before they were expanded when built need this
+    expand_disk(&redoxer_bin, qemu_disk_size())?;
 
-            expand_disk(&redoxer_bin, qemu_disk_size())?;
-        }
+    fs::create_dir_all(&dest_dir).context("unable to create redoxer dir")?;
 
-        fs::create_dir_all(&dest_dir).context("unable to create redoxer dir")?;
+    // init scripts provided by the install-config are kept
+    for init_script in ["usr/lib/run_redoxer.ion", "usr/lib/init.d/30_redoxer"] {
+        extract_path(&redoxer_bin, init_script, &dest_dir.join(init_script))?;
+    }
+    drop(phase);
 
-        {
-            let redoxfs_opt = if fuse {
-                Some(RedoxFs::new(&redoxer_bin, &dest_dir).context("unable to init redoxfs")?)
-            } else {
-                extract_tar(&base_file, &dest_dir)?;
-                None
-            };
+    let mut wrapper = ExecWrapper::default();
+    if config.stdin {
+        // redox has no channel to stream stdin, it is stored on the disk
+        if io::stdin().is_terminal() {
+            bail!("--stdin reads stdin until EOF, redirect it from a file or a pipe");
+        }
+        progress!("redoxer: reading stdin until EOF");
+        let mut stdin = Vec::new();
+        io::stdin()
+            .read_to_end(&mut stdin)
+            .context("unable to read stdin")?;
+        wrapper.stdin = Some(stdin);
+    }
+    wrapper.split_output = config.split_output;
+    wrapper.env = config.env.clone();
+    wrapper.cwd = config.cwd.clone();
+    wrapper.repeat = config.repeat;
+    write_redoxerd_config(
+        &dest_dir,
+        &config.arguments,
+        config.folders.get("root").map(std::string::String::as_str),
+        Some(&wrapper),
+    )?;
+    if vm {
+        write_redoxer_agent(&dest_dir)?;
+    }
 
-            write_redoxerd_config(
-                &dest_dir,
-                &config.arguments,
-                config.folders.get("root").map(std::string::String::as_str),
-            )?;
+    let _phase = timings::phase("folder copy");
+    let redoxerd_files = [(format!("{}/", dest_dir.display()), String::new())];
+    populate_image(&redoxer_bin, &redoxerd_files, &CopyFilter::default())
+        .context("unable to populate redoxer bin")?;
 
-            for (sysroot, folder) in config.folders.iter() {
-                eprintln!("redoxer: copying '{folder}' to '/{sysroot}'");
+    let mut copies = Vec::new();
+    for (sysroot, folder) in config.folders.iter() {
+        progress!("redoxer: copying '{folder}' to '/{sysroot}'");
+        copies.push((folder.clone(), sysroot.clone()));
+    }
+    populate_image(&redoxer_bin, &copies, &config.filter)
+        .context("unable to populate redoxer bin")?;
+
+    Ok(RedoxerImage {
+        disk: redoxer_bin,
+        dir: dest_dir,
+        gui,
+    })
+}
 
-                let dst_dir = dest_dir.join(sysroot);
-                if !dst_dir.is_dir() {
-                    fs::create_dir_all(&dst_dir)
-                        .context("unable to create destination directory")?;
+/// build the qemu command booting `image`, with the debug console written to `log`.
+/// The serial console is attached to stdio, or to a unix socket at `serial` if given.
+pub(crate) fn qemu_command(
+    config: &RedoxerExecConfig,
+    image: &RedoxerImage,
+    log: &Path,
+    serial: Option<&Path>,
+) -> anyhow::Result<Command> {
+    let mut command = Command::new(qemu_binary(config));
+
+    let chardev = format!("file,id=log,path={}", log.display());
+    let drive = format!("file={},format=raw,if=virtio", image.disk.display());
+    let serial_chardev = serial.map(|path| {
+        format!(
+            "socket,id=debug,path={},server=on,wait=off,mux=on",
+            path.display()
+        )
+    });
+    let memory = config.profile.memory.to_string();
+    let cpus = config.profile.cpus.to_string();
+    let mut default_args = qemu_default_args();
+    for i in 1..default_args.len() {
+        match default_args[i - 1] {
+            "-m" => default_args[i] = &memory,
+            "-smp" => default_args[i] = &cpus,
+            "-machine" => {
+                if let Some(machine) = config.profile.machine.as_deref() {
+                    default_args[i] = machine;
                 }
-                Command::new("rsync")
-                    .arg("--archive")
-                    .arg(folder)
-                    .arg(&dst_dir)
-                    .status()
-                    .and_then(status_error)
-                    .context("rsync failed")?;
             }
-
-            if let Some(mut redoxfs) = redoxfs_opt {
-                redoxfs.unmount().context("unable to unmount")?;
+            _ => {}
+        }
+    }
+    if let Some(serial_chardev) = serial_chardev.as_deref() {
+        for arg in default_args.iter_mut() {
+            match *arg {
+                "mon:stdio" => *arg = "chardev:debug",
+                "stdio,id=debug,signal=off,mux=on" => *arg = serial_chardev,
+                _ => {}
             }
         }
-
-        if !fuse {
-            archive_image(
-                &redoxer_bin,
-                &dest_dir,
-                &bootloader_bin,
-                qemu_use_uefi(),
-                qemu_disk_size(),
-            )?;
+        if !default_args.contains(&serial_chardev) {
+            default_args.extend(["-chardev", serial_chardev]);
         }
+    }
+    default_args.extend(vec!["-chardev", &chardev, "-drive", &drive]);
+    if qemu_has_kvm() {
+        default_args.push("-accel");
+        default_args.push("kvm");
+    }
+    if !image.gui {
+        // -nographic would claim stdio, which is not available for a socket console
+        if serial.is_some() {
+            default_args.push("-display");
+            default_args.push("none");
+        } else {
+            default_args.push("-nographic");
+        }
+        default_args.push("-vga");
+        default_args.push("none");
+    }
 
-        let redoxer_log = tempdir.path().join("redoxer.log");
-        let mut command = Command::new(qemu_binary);
+    apply_qemu_args(&mut command, default_args, config)?;
+    if let Some(port) = config.gdb {
+        // paused until gdb connects and continues
+        command.arg("-gdb").arg(format!("tcp::{port}")).arg("-S");
+    }
 
-        let chardev = format!("file,id=log,path={}", redoxer_log.display());
-        let drive = format!("file={},format=raw,if=virtio", redoxer_bin.display());
-        let mut default_args = qemu_default_args();
-        default_args.extend(vec!["-chardev", &chardev, "-drive", &drive]);
-        if kvm {
-            default_args.push("-accel");
-            default_args.push("kvm");
+    Ok(command)
+}
+
+/// riscv64 has no isa-debug-exit, the guest exits with semihosting or powers
+/// off through the sifive_test finisher of the virt machine, so the result is
+/// mapped to the x86 codes, 51 for success and 53 for failure, when qemu exits
+/// with 0 after the command succeeded or with the exit status of the command,
+/// any other exit is a qemu failure
+fn guest_exit_code(code: Option<i32>, exit_status: Option<i32>) -> Option<i32> {
+    if target() != "riscv64gc-unknown-redox" {
+        return code;
+    }
+    riscv_exit_code(code, exit_status)
+}
+
+fn riscv_exit_code(code: Option<i32>, exit_status: Option<i32>) -> Option<i32> {
+    match (code, exit_status) {
+        // finisher pass, or semihosting exit 0
+        (Some(0), Some(0)) => Some(51),
+        // finisher fail or semihosting exit with the status, truncated by the host
+        (Some(code), Some(status)) if code != 0 && code == status & 0xff => Some(53),
+        // powered off before the command reported its exit status
+        (Some(0), None) => Some(53),
+        _ => None,
+    }
+}
+
+/// wait for qemu until `timeout` passes, then terminate it and return None
+fn wait_timeout(
+    child: &mut process::Child,
+    timeout: Duration,
+) -> anyhow::Result<Option<process::ExitStatus>> {
+    let Some(deadline) = Instant::now().checked_add(timeout) else {
+        // too far away to ever pass
+        return Ok(Some(child.wait().context("unable to get redoxer status")?));
+    };
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().context("unable to get redoxer status")? {
+            return Ok(Some(status));
         }
-        if !gui {
-            default_args.push("-nographic");
-            default_args.push("-vga");
-            default_args.push("none");
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // SIGTERM lets qemu restore the terminal and flush the log
+    let _ = Command::new("kill").arg(child.id().to_string()).status();
+    let grace = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < grace {
+        if child.try_wait()?.is_some() {
+            return Ok(None);
         }
+        thread::sleep(Duration::from_millis(100));
+    }
+    child.kill().context("unable to kill qemu")?;
+    child.wait()?;
+    Ok(None)
+}
 
-        apply_qemu_args(
-            &mut command,
-            default_args,
-            config.qemu_args.as_ref().map(|s| s.split(" ").collect()),
+/// run the command, again in a fresh vm while it fails and retries are left
+fn run(config: &RedoxerExecConfig) -> anyhow::Result<RunStatus> {
+    // test results of the failed attempts
+    let mut failures = Vec::new();
+    let mut attempt = 0;
+    loop {
+        let (status, suites) = inner(config, attempt == 0)?;
+        let passed = status == RunStatus::Guest(0);
+        if passed && attempt > 0 {
+            progress!("## redoxer (flaky, passed on attempt {}) ##", attempt + 1);
+        }
+        if passed || attempt == config.retries {
+            if let Some(mut suites) = suites {
+                report::mark_flaky(&mut suites, &failures);
+                report::write(&config.reports, &suites)?;
+            }
+            return Ok(status);
+        }
+        failures.extend(suites.into_iter().flatten());
+        attempt += 1;
+        progress!(
+            "redoxer: retrying in a fresh vm, attempt {} of {}",
+            attempt + 1,
+            config.retries + 1
         );
+    }
+}
+
+/// boot the command, returns how it ended and the test results when
+/// reports are written
+fn inner(
+    config: &RedoxerExecConfig,
+    reuse_vm: bool,
+) -> anyhow::Result<(RunStatus, Option<Vec<TestSuite>>)> {
+    let start = Instant::now();
+    if reuse_vm && let Some(status) = vm::exec(config)? {
+        timings::record("command in vm", start.elapsed());
+        return Ok((status, None));
+    }
+
+    // it is unusual to request custom qemu binary
+    let qemu_verbose = config.qemu_binary.is_some();
+
+    check_requirements(config)?;
 
-        if config.output.as_ref().is_some_and(|s| s == "-") {
-            command.stdout(Stdio::null());
+    if config.network != Network::Full {
+        progress!("redoxer: network is {}", config.network);
+    }
+
+    progress!("redoxer: creating temporary disk");
+    let tempdir = tempfile::tempdir().context("unable to create tempdir")?;
+    let image = prepare_image(config, tempdir.path(), false)?;
+
+    let mut suites = None;
+    let run_status = {
+        let redoxer_log = tempdir.path().join("redoxer.log");
+        let mut command = qemu_command(config, &image, &redoxer_log, None)?;
+
+        let hide_console = config.split_output || config.output.as_ref().is_some_and(|s| s == "-");
+        // the serial console is always read, to find crashes in it
+        command.stdout(Stdio::piped());
+        let console_mode = if hide_console {
             command.stderr(Stdio::null());
+            ConsoleMode::Hide
+        } else if config.quiet_boot {
+            ConsoleMode::QuietBoot
+        } else {
+            ConsoleMode::Show
+        };
+        if config.stdin {
+            // stdin was already consumed, keep it away from the serial console
+            command.stdin(Stdio::null());
         }
 
         if qemu_verbose {
             eprintln!("{command:?}");
         }
-        let status = command.status().context("unable to get redoxer status")?;
+        if let Some(port) = config.gdb {
+            // per run, so concurrent runs do not overwrite each other
+            let gdb_dir = tempdir.path().join("gdb");
+            let script = gdb::write_script(config, &image, port, &gdb_dir)?;
+            progress!(
+                "redoxer: gdb script and symbols are in {}, removed after the run",
+                gdb_dir.display()
+            );
+            progress!("redoxer: qemu is waiting for gdb on port {port}, attach with:");
+            progress!("    gdb -x {}", script.display());
+        }
+        let spawned = Instant::now();
+        let mut child = command.spawn().context("unable to spawn qemu")?;
+        let console = child
+            .stdout
+            .take()
+            .map(|stdout| thread::spawn(move || BootConsole::run(stdout, console_mode)));
+        // a debugging session takes as long as it needs
+        let status = match config.timeout.filter(|_| config.gdb.is_none()) {
+            Some(timeout) => wait_timeout(&mut child, timeout)?,
+            None => Some(child.wait().context("unable to get redoxer status")?),
+        };
+        let exited = Instant::now();
+
+        let console = match console {
+            Some(console) => Some(
+                console
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("console thread panicked"))??,
+            ),
+            None => None,
+        };
+        timings::record_qemu(
+            spawned,
+            console.as_ref().and_then(BootConsole::started),
+            console.as_ref().and_then(BootConsole::finished),
+            exited,
+        );
 
-        eprintln!();
+        progress!();
 
-        let code = match status.code() {
-            Some(51) => {
-                eprintln!("## redoxer (success) ##");
-                0
+        let log = fs::read(&redoxer_log)?;
+        // aarch64 and riscv64 have no debugcon device, the log of the command
+        // with its exit status and output streams only reaches the serial console
+        let guest_log = match &console {
+            Some(console) if log.is_empty() => GuestLog::parse(console.command()),
+            _ => GuestLog::parse(&log),
+        };
+        let exit_status = guest_log.exit_status.or_else(|| {
+            console
+                .as_ref()
+                .and_then(|console| GuestLog::parse(console.serial()).exit_status)
+        });
+        let run_status = match status {
+            None => {
+                progress!(
+                    "## redoxer (failure, timed out after {:?}) ##",
+                    config.timeout.unwrap_or_default()
+                );
+                RunStatus::Timeout
             }
-            Some(53) => {
-                eprintln!("## redoxer (failure) ##");
-                1
+            Some(status) => match (guest_exit_code(status.code(), exit_status), exit_status) {
+                (Some(51), _) => {
+                    progress!("## redoxer (success) ##");
+                    RunStatus::Guest(0)
+                }
+                (Some(53), Some(exit_status)) if exit_status != 0 => {
+                    progress!("## redoxer (failure, exit status {exit_status}) ##");
+                    RunStatus::Guest(exit_status)
+                }
+                (Some(53), _) => {
+                    progress!("## redoxer (failure) ##");
+                    RunStatus::Failure
+                }
+                _ => {
+                    progress!("## redoxer (failure, qemu exit status {status}) ##");
+                    RunStatus::Qemu
+                }
+            },
+        };
+        let code = run_status.exit_code();
+
+        // the command did not report its own status, look for what broke
+        let crash = match &console {
+            Some(console) if code != 0 && exit_status.is_none_or(|s| s == 0) => {
+                crash::analyze(console.serial(), console.boot(), &guest_log.log)
             }
-            _ => {
-                eprintln!("## redoxer (failure, qemu exit status {status}) ##");
-                2
+            _ => None,
+        };
+        if console_mode == ConsoleMode::QuietBoot
+            && code != 0
+            && let Some(console) = &console
+        {
+            console.print_boot()?;
+        }
+        let run_status = match &crash {
+            Some(crash) => {
+                eprint!("{}", crash.summary());
+                RunStatus::Crash(crash.kind)
             }
+            None => run_status,
         };
+        let code = run_status.exit_code();
+        if code != 0
+            && let Some(repeat) = config.repeat
+            && let Some(line) = guest_log
+                .log
+                .split(|b| *b == b'\n')
+                .rfind(|line| line.starts_with(REPEAT_MARKER))
+        {
+            let run = String::from_utf8_lossy(&line[REPEAT_MARKER.len()..]);
+            let run = run.trim_end().trim_end_matches(" ##");
+            progress!("redoxer: failed on run {run} of --repeat {repeat}");
+        }
+        if code != 0 && config.network != Network::Full {
+            progress!(
+                "redoxer: network was {}, the failure may come from blocked network access",
+                config.network
+            );
+        }
 
+        let crash_summary = crash.as_ref().map(Crash::summary).unwrap_or_default();
         match config.output.as_deref() {
-            Some("-") => print!("{}", fs::read_to_string(&redoxer_log)?),
-            Some(output) => fs::copy(&redoxer_log, output).map(|_| {})?,
+            Some("-") => {
+                let mut stdout = io::stdout();
+                stdout.write_all(&guest_log.log)?;
+                stdout.write_all(crash_summary.as_bytes())?;
+            }
+            Some(output) => fs::write(output, [&guest_log.log, crash_summary.as_bytes()].concat())?,
             None => {}
         }
 
-        code
-    };
+        if !config.reports.is_empty() {
+            let output = guest_log.stdout.as_deref().unwrap_or(&guest_log.log);
+            let mut parsed = report::parse(&report::suite_name(&config.arguments[0]), output);
+            if config.repeat.is_some() {
+                report::merge_repeats(&mut parsed);
+            }
+            let suite = parsed.last_mut().unwrap();
+            let failure = match (&crash, run_status) {
+                (Some(crash), _) => Some(crash.summary()),
+                (None, RunStatus::Guest(0)) => None,
+                (None, RunStatus::Timeout) => Some("timed out".to_string()),
+                (None, _) if !suite.has_failure() => Some(format!("exit status {code}")),
+                (None, _) => None,
+            };
+            if let Some(failure) = failure {
+                suite.add_failure(&failure);
+            }
+            suites = Some(parsed);
+        }
 
-    if code == 0 && !config.artifacts.is_empty() {
-        let redoxfs_opt = if fuse {
-            Some(RedoxFs::new(&redoxer_bin, &dest_dir).context("unable to init redoxfs")?)
-        } else {
-            unimplemented!()
-        };
+        if config.split_output {
+            match (guest_log.stdout, guest_log.stderr) {
+                (Some(stdout), Some(stderr)) => {
+                    io::stdout().write_all(&stdout)?;
+                    io::stderr().write_all(&stderr)?;
+                }
+                _ => {
+                    // the command did not finish, show what the guest printed
+                    eprintln!("redoxer: guest output was not captured");
+                    io::stderr().write_all(&guest_log.log)?;
+                }
+            }
+        }
+
+        run_status
+    };
 
+    let _phase = timings::phase("copy back");
+    if run_status == RunStatus::Guest(0) {
         for (sysroot, folder) in config.artifacts.iter() {
-            eprintln!("redoxer: copying '/{sysroot}' to '{folder}'");
+            progress!("redoxer: copying '/{sysroot}' to '{folder}'");
 
             let dst_dir = Path::new(folder);
             if !dst_dir.is_dir() {
                 fs::create_dir_all(dst_dir).context("unable to create destination directory")?;
             }
-            Command::new("rsync")
-                .arg("--archive")
-                .arg(format!("{}/", dest_dir.join(sysroot).display()))
-                .arg(dst_dir)
-                .status()
-                .and_then(status_error)
-                .context("rsync failed")?;
-        }
-
-        if let Some(mut redoxfs) = redoxfs_opt {
-            redoxfs.unmount().context("unable to unmount")?;
+            if !extract_path(&image.disk, sysroot, dst_dir)? {
+                bail!("artifact '/{sysroot}' not found in redoxer bin");
+            }
         }
     }
 
     tempdir.close()?;
 
-    Ok(code)
+    Ok((run_status, suites))
 }
 
 fn usage() -> ! {
-    eprintln!("redoxer exec [-f|--folder folder] [-f|--folder folder:/path/in/redox] [-a|--artifact folder] [-a|--artifact folder:/path/in/redox] [-g|--gui] [-h|--help] [-i|--install-config] [-o|--output file] [--] <command> [arguments]...");
+    eprintln!("redoxer exec [-f|--folder folder] [-f|--folder folder:/path/in/redox] [--include pattern] [--exclude pattern] [--gitignore] [-a|--artifact folder] [-a|--artifact folder:/path/in/redox] [-g|--gui] [-h|--help] [-i|--install-config] [--profile name] [--memory size] [--cpus count] [--machine type] [--network none|isolated|full] [--forward tcp|udp:HOSTPORT:GUESTPORT] [--gdb[=port]] [-o|--output file] [--status-file file] [--report junit|json=file] [--retries N] [--repeat N] [-t|--timeout duration] [-e|--env KEY=VALUE] [--env-pass NAME] [-C|--cwd /path/in/redox] [--cwd-from-host] [--stdin] [-s|--split-output] [-q|--quiet-boot] [--timings] [--] <command> [arguments]...");
     process::exit(1);
 }
 
+/// Host port forwarded to a guest port with --forward
+#[derive(Clone)]
+pub struct Forward {
+    pub protocol: String,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+impl std::fmt::Display for Forward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.protocol, self.host_port, self.guest_port
+        )
+    }
+}
+
+/// Network of the guest, chosen with --network
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Network {
+    // no network device at all
+    None,
+    // user mode network restricted to forwarded ports
+    Isolated,
+    // user mode network with access to the host and internet
+    #[default]
+    Full,
+}
+
+impl Network {
+    fn parse(network: &str) -> anyhow::Result<Self> {
+        match network {
+            "none" => Ok(Self::None),
+            "isolated" => Ok(Self::Isolated),
+            "full" => Ok(Self::Full),
+            _ => bail!("network {network:?} must be 'none', 'isolated' or 'full'"),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Isolated => "isolated",
+            Self::Full => "full",
+        })
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct RedoxerExecConfig {
     // Qemu config
     pub qemu_binary: Option<String>,
     pub qemu_args: Option<String>,
+    pub qemu_args_append: Option<String>,
+    pub qemu_args_remove: Option<String>,
     pub fuse: bool,
+    pub reflink: bool,
     // Installer config
     pub config_name: String,
     pub config_toml: String,
+    // File given with --install-config, passed on to runners and parallel vms
+    pub config_file: Option<String>,
     // Folders to copy (host -> qemu)
     pub folders: HashMap<String, String>,
+    // Patterns for the copied folders
+    pub filter: CopyFilter,
     // Folders to extract (qemu -> host)
     pub artifacts: HashMap<String, String>,
+    // Memory, cpus and machine type of the guest
+    pub profile: Profile,
+    // Hardware flags, passed on by to_args instead of the profile
+    pub profile_args: ProfileArgs,
+    // Network of the guest
+    pub network: Network,
+    // Network flag, passed on by to_args instead of REDOXER_NETWORK
+    pub network_arg: Option<Network>,
+    // Ports forwarded from the host to the guest
+    pub forwards: Vec<Forward>,
+    // Port of the qemu gdbstub, qemu waits for gdb when set
+    pub gdb: Option<u16>,
     // Output log
     pub output: Option<String>,
+    // How the run ended, written after the command
+    pub status_file: Option<String>,
+    // Kill qemu after this duration
+    pub timeout: Option<Duration>,
+    // Test reports written after the command
+    pub reports: Vec<Report>,
+    // Boot a fresh vm again while the command fails
+    pub retries: u32,
+    // Run the command this many times in one boot, until it fails
+    pub repeat: Option<u32>,
+    // Only run the tests that failed in the last `redoxer test`
+    pub rerun_failed: bool,
+    // Test binaries run in parallel vms by `redoxer test`
+    pub jobs: Option<usize>,
+    // Feed host stdin, read until EOF before booting, to the command
+    pub stdin: bool,
+    // Write stdout and stderr of the command to host stdout and stderr
+    pub split_output: bool,
+    // Hide the serial console until the command runs
+    pub quiet_boot: bool,
+    // Print how long each phase of the run took
+    pub timings: bool,
+    // Environment variables for the command
+    pub env: Vec<(String, String)>,
+    // Working directory of the command
+    pub cwd: Option<String>,
+    // Use the guest path of the host working directory as cwd
+    pub cwd_from_host: bool,
     // Commands to execute
     pub arguments: Vec<String>,
 }
@@ -553,13 +1087,55 @@ impl RedoxerExecConfig {
             Ok(())
         }
 
+        fn parse_forward(forward: &str) -> anyhow::Result<Forward> {
+            let parts: Vec<&str> = forward.split(':').collect();
+            let (protocol, host_port, guest_port) = match parts[..] {
+                [protocol @ ("tcp" | "udp"), host_port, guest_port] => {
+                    (protocol, host_port, guest_port)
+                }
+                _ => {
+                    bail!("--forward must be 'tcp:HOSTPORT:GUESTPORT' or 'udp:HOSTPORT:GUESTPORT'")
+                }
+            };
+            let port = |port: &str| match port.parse::<u16>() {
+                Ok(port) if port != 0 => Ok(port),
+                _ => Err(anyhow::anyhow!("invalid port {port:?} on --forward")),
+            };
+            Ok(Forward {
+                protocol: protocol.to_string(),
+                host_port: port(host_port)?,
+                guest_port: port(guest_port)?,
+            })
+        }
+
+        fn set_env(env: &mut Vec<(String, String)>, key: &str, value: &str) -> anyhow::Result<()> {
+            if key.is_empty() || key.contains(['=', '\n']) || value.contains('\n') {
+                bail!("invalid environment variable {key:?}={value:?}");
+            }
+            env.retain(|(k, _)| k != key);
+            env.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+
         let mut config = RedoxerExecConfig {
             qemu_binary: var("REDOXER_QEMU_BINARY").ok(),
             qemu_args: var("REDOXER_QEMU_ARGS").ok(),
+            qemu_args_append: var("REDOXER_QEMU_ARGS_APPEND").ok(),
+            qemu_args_remove: var("REDOXER_QEMU_ARGS_REMOVE").ok(),
             fuse: parse_bool_env("REDOXER_USE_FUSE")
                 .unwrap_or_else(|| Path::new("/dev/fuse").exists()),
+            reflink: parse_bool_env("REDOXER_USE_REFLINK").unwrap_or(cfg!(target_os = "linux")),
             config_name: "base".into(),
             config_toml: BASE_TOML.into(),
+            timeout: var("REDOXER_TIMEOUT")
+                .ok()
+                .map(|t| parse_duration(&t))
+                .transpose()?,
+            network: var("REDOXER_NETWORK")
+                .ok()
+                .map(|n| Network::parse(&n))
+                .transpose()?
+                .unwrap_or_default(),
             // other options should be passed from args
             ..Default::default()
         };
@@ -572,6 +1148,47 @@ impl RedoxerExecConfig {
                     Some(folder) => parse_folder(&mut config.folders, folder, "--folder")?,
                     None => bail!("--folder requires a path to a directory"),
                 },
+                ("--gdb", true) => config.gdb = Some(GDB_PORT),
+                (gdb, true) if gdb.starts_with("--gdb=") => match gdb[6..].parse::<u16>() {
+                    Ok(port) if port != 0 => config.gdb = Some(port),
+                    _ => bail!("--gdb port {:?} is not valid", &gdb[6..]),
+                },
+                ("--profile", true) => match args.next() {
+                    Some(name) => config.profile_args.name = Some(name),
+                    None => bail!("--profile requires a profile name"),
+                },
+                ("--memory", true) => match args.next() {
+                    Some(size) => config.profile_args.memory = Some(parse_memory(&size)?),
+                    None => bail!("--memory requires a size like 512M or 2G"),
+                },
+                ("--cpus", true) => match args.next().map(|c| c.parse::<u32>()) {
+                    Some(Ok(count)) => config.profile_args.cpus = Some(count),
+                    _ => bail!("--cpus requires a number of cpus"),
+                },
+                ("--machine", true) => match args.next() {
+                    Some(name) => config.profile_args.machine = Some(name),
+                    None => bail!("--machine requires a machine type"),
+                },
+                ("--network", true) => match args.next() {
+                    Some(network) => {
+                        config.network = Network::parse(&network)?;
+                        config.network_arg = Some(config.network);
+                    }
+                    None => bail!("--network requires none, isolated or full"),
+                },
+                ("--forward", true) => match args.next() {
+                    Some(forward) => config.forwards.push(parse_forward(&forward)?),
+                    None => bail!("--forward requires tcp:HOSTPORT:GUESTPORT"),
+                },
+                ("--include", true) => match args.next() {
+                    Some(pattern) => config.filter.rules.push((true, pattern)),
+                    None => bail!("--include requires a pattern"),
+                },
+                ("--exclude", true) => match args.next() {
+                    Some(pattern) => config.filter.rules.push((false, pattern)),
+                    None => bail!("--exclude requires a pattern"),
+                },
+                ("--gitignore", true) => config.filter.gitignore = true,
                 ("-a" | "--artifact", true) => match args.next() {
                     Some(folder) => parse_folder(&mut config.artifacts, folder, "--artifact")?,
                     None => bail!("--folder requires a path to a directory"),
@@ -579,6 +1196,7 @@ impl RedoxerExecConfig {
                 ("-g" | "--gui", true) => {
                     config.config_name = "gui".into();
                     config.config_toml = GUI_TOML.into();
+                    config.config_file = None;
                 }
                 ("-i" | "--install-config", true) => match args.next() {
                     Some(file) => {
@@ -590,6 +1208,10 @@ impl RedoxerExecConfig {
                         }
                         config.config_toml =
                             fs::read_to_string(path).expect("unable to read --install-config file");
+                        // runners and parallel vms may start in another directory
+                        let path = fs::canonicalize(path)
+                            .context("unable to resolve --install-config file")?;
+                        config.config_file = Some(path.to_string_lossy().into_owned());
                     }
                     None => bail!("--output requires a path to a directory"),
                 },
@@ -598,6 +1220,59 @@ impl RedoxerExecConfig {
                     Some(output) => config.output = Some(output),
                     None => bail!("--output requires a path to a directory"),
                 },
+                ("--status-file", true) => match args.next() {
+                    Some(status_file) => config.status_file = Some(status_file),
+                    None => bail!("--status-file requires a path to a file"),
+                },
+                ("-e" | "--env", true) => {
+                    match args.next().as_deref().and_then(|e| e.split_once('=')) {
+                        Some((key, value)) => set_env(&mut config.env, key, value)?,
+                        None => bail!("--env requires KEY=VALUE"),
+                    }
+                }
+                ("--env-pass", true) => match args.next() {
+                    Some(key) => {
+                        if let Ok(value) = var(&key) {
+                            set_env(&mut config.env, &key, &value)?;
+                        }
+                    }
+                    None => bail!("--env-pass requires a variable name"),
+                },
+                ("-C" | "--cwd", true) => match args.next() {
+                    Some(cwd) => {
+                        if !cwd.starts_with('/') || cwd.contains(['\'', '\n']) {
+                            bail!("--cwd must be an absolute path without quotes");
+                        }
+                        config.cwd = Some(cwd);
+                    }
+                    None => bail!("--cwd requires a path in redox"),
+                },
+                ("--cwd-from-host", true) => config.cwd_from_host = true,
+                ("--stdin", true) => config.stdin = true,
+                ("-s" | "--split-output", true) => config.split_output = true,
+                ("-q" | "--quiet-boot", true) => config.quiet_boot = true,
+                ("--timings", true) => config.timings = true,
+                ("-t" | "--timeout", true) => match args.next() {
+                    Some(timeout) => config.timeout = Some(parse_duration(&timeout)?),
+                    None => bail!("--timeout requires a duration"),
+                },
+                ("--report", true) => match args.next() {
+                    Some(report) => config.reports.push(Report::parse(&report)?),
+                    None => bail!("--report requires junit=path or json=path"),
+                },
+                ("--retries", true) => match args.next().map(|r| r.parse::<u32>()) {
+                    Some(Ok(retries)) => config.retries = retries,
+                    _ => bail!("--retries requires a number of retries"),
+                },
+                ("--repeat", true) => match args.next().map(|r| r.parse::<u32>()) {
+                    Some(Ok(repeat)) if repeat != 0 => config.repeat = Some(repeat),
+                    _ => bail!("--repeat requires a number of runs"),
+                },
+                ("--rerun-failed", true) => config.rerun_failed = true,
+                ("-j" | "--jobs", true) => match args.next().map(|j| j.parse::<usize>()) {
+                    Some(Ok(jobs)) if jobs != 0 => config.jobs = Some(jobs),
+                    _ => bail!("--jobs requires a number of vms"),
+                },
                 ("--", true) => matching = false,
                 _ => {
                     matching = false;
@@ -606,6 +1281,31 @@ impl RedoxerExecConfig {
             }
         }
 
+        for (name, args) in [
+            ("REDOXER_QEMU_ARGS", &config.qemu_args),
+            ("REDOXER_QEMU_ARGS_APPEND", &config.qemu_args_append),
+            ("REDOXER_QEMU_ARGS_REMOVE", &config.qemu_args_remove),
+        ] {
+            if let Some(args) = args {
+                split_args(args).with_context(|| format!("invalid {name}"))?;
+            }
+        }
+
+        config.profile = config.profile_args.profile()?;
+        config.profile.validate().context("invalid vm hardware")?;
+
+        if config.network == Network::None && !config.forwards.is_empty() {
+            bail!("--forward requires a network, but --network is none");
+        }
+
+        for key in ENV_PASS_DEFAULT {
+            if !config.env.iter().any(|(k, _)| k == key)
+                && let Ok(value) = var(key)
+            {
+                set_env(&mut config.env, key, &value)?;
+            }
+        }
+
         if !config.folders.contains_key("root")
             && let Some(cmd) = config.arguments.first()
             && Path::new(cmd).is_file()
@@ -620,6 +1320,10 @@ impl RedoxerExecConfig {
             }
         }
 
+        if config.cwd.is_none() && config.cwd_from_host {
+            config.cwd = config.guest_path(&std::env::current_dir()?)?;
+        }
+
         #[cfg(feature = "cli-pkg")]
         if let Some(sysroot) = crate::pkg::get_sysroot() {
             parse_folder(
@@ -629,13 +1333,33 @@ impl RedoxerExecConfig {
             )?
         }
 
-        if !config.artifacts.is_empty() && !config.fuse {
-            bail!("--artifact requires REDOXER_USE_FUSE=true");
-        }
-
         Ok(config)
     }
 
+    /// path inside redox where a host path was copied by the folders
+    pub fn guest_path(&self, host_path: &Path) -> io::Result<Option<String>> {
+        let host_path = fs::canonicalize(host_path)?;
+        let mut best: Option<(usize, String)> = None;
+        for (sysroot, folder) in self.folders.iter() {
+            let folder_path = fs::canonicalize(folder)?;
+            // rsync copies the folder itself unless it ends with a slash
+            let mut guest_dir = PathBuf::from("/").join(sysroot);
+            if !folder.ends_with('/')
+                && let Some(name) = folder_path.file_name()
+            {
+                guest_dir.push(name);
+            }
+            if let Ok(relative) = host_path.strip_prefix(&folder_path) {
+                let depth = folder_path.components().count();
+                if best.as_ref().is_none_or(|(d, _)| depth > *d) {
+                    let guest_path = guest_dir.join(relative);
+                    best = Some((depth, guest_path.to_string_lossy().into_owned()));
+                }
+            }
+        }
+        Ok(best.map(|(_, path)| path))
+    }
+
     pub fn to_args(&self) -> Vec<String> {
         let mut args = Vec::new();
 
@@ -644,12 +1368,53 @@ impl RedoxerExecConfig {
             args.push(format!("{host_dir}:/{sysroot}"));
         }
 
+        for (include, pattern) in &self.filter.rules {
+            args.push(if *include { "--include" } else { "--exclude" }.to_string());
+            args.push(pattern.clone());
+        }
+        if self.filter.gitignore {
+            args.push("--gitignore".to_string());
+        }
+
+        // the defaults of the environment are left to the runner
+        if let Some(ref name) = self.profile_args.name {
+            args.push("--profile".to_string());
+            args.push(name.clone());
+        }
+        if let Some(memory) = self.profile_args.memory {
+            args.push("--memory".to_string());
+            args.push(format!("{memory}M"));
+        }
+        if let Some(cpus) = self.profile_args.cpus {
+            args.push("--cpus".to_string());
+            args.push(cpus.to_string());
+        }
+        if let Some(ref machine) = self.profile_args.machine {
+            args.push("--machine".to_string());
+            args.push(machine.clone());
+        }
+
+        if let Some(network) = self.network_arg {
+            args.push("--network".to_string());
+            args.push(network.to_string());
+        }
+        if let Some(port) = self.gdb {
+            args.push(format!("--gdb={port}"));
+        }
+        for forward in &self.forwards {
+            args.push("--forward".to_string());
+            args.push(forward.to_string());
+        }
+
         for (sysroot, host_dir) in &self.artifacts {
             args.push("--artifact".to_string());
             args.push(format!("{host_dir}:/{sysroot}"));
         }
 
-        if self.config_name == "gui" {
+        if let Some(ref config_file) = self.config_file {
+            args.push("--install-config".to_string());
+            args.push(config_file.clone());
+        } else if self.config_name == "gui" {
             args.push("--gui".to_string());
         }
 
@@ -658,6 +1423,59 @@ impl RedoxerExecConfig {
             args.push(output.clone());
         }
 
+        if let Some(ref status_file) = self.status_file {
+            args.push("--status-file".to_string());
+            args.push(status_file.clone());
+        }
+
+        if let Some(ref cwd) = self.cwd {
+            args.push("--cwd".to_string());
+            args.push(cwd.clone());
+        } else if self.cwd_from_host {
+            args.push("--cwd-from-host".to_string());
+        }
+
+        for (key, value) in &self.env {
+            args.push("--env".to_string());
+            args.push(format!("{key}={value}"));
+        }
+
+        for report in &self.reports {
+            args.push("--report".to_string());
+            args.push(report.to_string());
+        }
+
+        if self.retries != 0 {
+            args.push("--retries".to_string());
+            args.push(self.retries.to_string());
+        }
+
+        if let Some(repeat) = self.repeat {
+            args.push("--repeat".to_string());
+            args.push(repeat.to_string());
+        }
+
+        if self.stdin {
+            args.push("--stdin".to_string());
+        }
+
+        if self.split_output {
+            args.push("--split-output".to_string());
+        }
+
+        if self.quiet_boot {
+            args.push("--quiet-boot".to_string());
+        }
+
+        if self.timings {
+            args.push("--timings".to_string());
+        }
+
+        if let Some(timeout) = self.timeout {
+            args.push("--timeout".to_string());
+            args.push(format!("{}ms", timeout.as_millis()));
+        }
+
         if !self.arguments.is_empty() {
             args.push("--".to_string());
 
@@ -670,6 +1488,29 @@ impl RedoxerExecConfig {
     }
 }
 
+/// parse durations like "90", "90s", "1500ms", "5m", "1h" or "30d"
+pub(crate) fn parse_duration(duration: &str) -> anyhow::Result<Duration> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let Ok(value) = value.parse::<u64>() else {
+        bail!("invalid duration {duration:?}");
+    };
+    let seconds = match unit {
+        "ms" => return Ok(Duration::from_millis(value)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("invalid duration unit in {duration:?}, expected ms, s, m, h or d"),
+    };
+    match value.checked_mul(seconds) {
+        Some(value) => Ok(Duration::from_secs(value)),
+        None => bail!("duration {duration:?} is too large"),
+    }
+}
+
 pub fn main(args: &[String]) {
     let config = match RedoxerExecConfig::new(args.iter().skip(2).cloned()) {
         Ok(config) => config,
@@ -682,13 +1523,53 @@ pub fn main(args: &[String]) {
     if config.arguments.is_empty() {
         usage();
     }
-    match inner(&config) {
-        Ok(code) => {
-            process::exit(code);
-        }
+    if config.jobs.is_some() || config.rerun_failed {
+        eprintln!("redoxer exec: --jobs and --rerun-failed only apply to redoxer test");
+        process::exit(1);
+    }
+    if config.stdin && config.retries != 0 {
+        eprintln!("redoxer exec: --stdin cannot be used with --retries, stdin is read once");
+        process::exit(1);
+    }
+    crate::set_quiet(config.split_output);
+    let result = run(&config);
+    if config.timings {
+        timings::print();
+    }
+    let (result, code, crash) = match result {
+        Ok(status) => (status.result(), status.exit_code(), status.crash()),
         Err(err) => {
             eprintln!("redoxer exec: {err:#}");
-            process::exit(3);
+            ("error", 3, None)
         }
+    };
+    if let Some(status_file) = &config.status_file
+        && let Err(err) = write_status_file(status_file, result, code, crash)
+    {
+        eprintln!("redoxer exec: unable to write status file: {err}");
+        process::exit(3);
+    }
+    process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn riscv_exit_codes() {
+        assert_eq!(riscv_exit_code(Some(0), Some(0)), Some(51));
+        assert_eq!(riscv_exit_code(Some(1), Some(1)), Some(53));
+        assert_eq!(riscv_exit_code(Some(44), Some(300)), Some(53));
+        assert_eq!(riscv_exit_code(Some(0), None), Some(53));
+        // not the status the command reported
+        assert_eq!(riscv_exit_code(Some(0), Some(1)), None);
+        assert_eq!(riscv_exit_code(Some(1), Some(0)), None);
+        assert_eq!(riscv_exit_code(Some(2), Some(1)), None);
+        // the x86 codes are no proof of the result on riscv64
+        assert_eq!(riscv_exit_code(Some(51), None), None);
+        assert_eq!(riscv_exit_code(Some(53), Some(0)), None);
+        // killed by a signal
+        assert_eq!(riscv_exit_code(None, Some(0)), None);
     }
 }
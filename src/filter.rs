@@ -0,0 +1,266 @@
+use std::fmt::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::{fs, io};
+
+// read in every copied directory with --gitignore
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+/// Include and exclude patterns for folders copied into the guest
+#[derive(Clone, Default)]
+pub struct CopyFilter {
+    // --include (true) and --exclude (false) patterns, the first match decides
+    pub rules: Vec<(bool, String)>,
+    // Skip files listed in .gitignore and .ignore files
+    pub gitignore: bool,
+}
+
+/// A gitignore style pattern
+///
+/// A trailing slash only matches directories, a pattern with a slash in it
+/// matches from the copied folder, any other pattern matches file names at
+/// any depth. `*` and `?` stop at slashes while `**` does not.
+struct Pattern {
+    glob: String,
+    anchored: bool,
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        Self {
+            glob: pattern.trim_start_matches('/').to_string(),
+            anchored: pattern.contains('/'),
+            dir_only,
+            negated,
+        }
+    }
+
+    fn matches(&self, rel_path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let text = if self.anchored {
+            rel_path
+        } else {
+            rel_path.rsplit('/').next().unwrap_or(rel_path)
+        };
+        glob_match(self.glob.as_bytes(), text.as_bytes())
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // "**/" also matches no directory at all
+            if let [b'/', after @ ..] = rest
+                && glob_match(after, text)
+            {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let end = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=end).any(|i| glob_match(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => glob_match(rest, text),
+            _ => false,
+        },
+        [b'[', class @ ..] => {
+            let Some(end) = class.iter().skip(1).position(|&c| c == b']') else {
+                // no closing bracket, match it literally
+                return text.first() == Some(&b'[') && glob_match(class, &text[1..]);
+            };
+            let (class, rest) = (&class[..end + 1], &class[end + 2..]);
+            match text {
+                [c, text @ ..] if *c != b'/' && class_match(class, *c) => glob_match(rest, text),
+                _ => false,
+            }
+        }
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => match text {
+            [t, text @ ..] if t == c => glob_match(rest, text),
+            _ => false,
+        },
+    }
+}
+
+fn class_match(class: &[u8], c: u8) -> bool {
+    let (negated, mut class) = match class {
+        [b'!' | b'^', class @ ..] => (true, class),
+        _ => (false, class),
+    };
+    let mut found = false;
+    while let [first, rest @ ..] = class {
+        match rest {
+            [b'-', last, rest @ ..] => {
+                found |= (*first..=*last).contains(&c);
+                class = rest;
+            }
+            _ => {
+                found |= *first == c;
+                class = rest;
+            }
+        }
+    }
+    found != negated
+}
+
+/// Filter state while walking one copied folder
+pub(crate) struct FilterWalk {
+    rules: Vec<(bool, Pattern)>,
+    gitignore: bool,
+    // patterns of the ignore files in the directories being walked
+    ignores: Vec<(String, Vec<Pattern>)>,
+}
+
+impl FilterWalk {
+    pub(crate) fn new(filter: &CopyFilter) -> Self {
+        Self {
+            rules: filter
+                .rules
+                .iter()
+                .map(|(include, pattern)| (*include, Pattern::parse(pattern)))
+                .collect(),
+            gitignore: filter.gitignore,
+            ignores: Vec::new(),
+        }
+    }
+
+    /// Read the ignore files of a directory, must be paired with `leave_dir`
+    pub(crate) fn enter_dir(&mut self, host_dir: &Path, rel_dir: &str) -> io::Result<()> {
+        let mut patterns = Vec::new();
+        if self.gitignore {
+            for name in IGNORE_FILES {
+                let data = match fs::read_to_string(host_dir.join(name)) {
+                    Ok(data) => data,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                };
+                patterns.extend(
+                    data.lines()
+                        .map(str::trim_end)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(Pattern::parse),
+                );
+            }
+        }
+        self.ignores.push((rel_dir.to_string(), patterns));
+        Ok(())
+    }
+
+    pub(crate) fn leave_dir(&mut self) {
+        self.ignores.pop();
+    }
+
+    /// `rel_path` is relative to the copied folder, or its parent when
+    /// the folder itself is copied
+    pub(crate) fn is_excluded(&self, rel_path: &str, is_dir: bool) -> bool {
+        for (include, pattern) in self.rules.iter() {
+            if pattern.matches(rel_path, is_dir) {
+                return !include;
+            }
+        }
+
+        if !self.gitignore {
+            return false;
+        }
+        if is_dir && rel_path.rsplit('/').next() == Some(".git") {
+            return true;
+        }
+        // like git, the last matching pattern of the deepest ignore file wins
+        let mut excluded = false;
+        for (rel_dir, patterns) in self.ignores.iter() {
+            let path = if rel_dir.is_empty() {
+                rel_path
+            } else {
+                match rel_path.strip_prefix(rel_dir.as_str()) {
+                    Some(path) => path.trim_start_matches('/'),
+                    None => continue,
+                }
+            };
+            for pattern in patterns.iter() {
+                if pattern.matches(path, is_dir) {
+                    excluded = !pattern.negated;
+                }
+            }
+        }
+        excluded
+    }
+}
+
+/// List the files `copies` would put in the guest, with their size and
+/// modification time, to tell whether a copy made earlier is still current
+pub(crate) fn manifest(copies: &[(String, String)], filter: &CopyFilter) -> io::Result<String> {
+    let mut walk = FilterWalk::new(filter);
+    let mut manifest = String::new();
+    for (host_path, guest_dir) in copies {
+        let path = Path::new(host_path);
+        writeln!(manifest, "{host_path} -> /{guest_dir}").unwrap();
+        if host_path.ends_with('/') && path.is_dir() {
+            manifest_dir(&mut walk, path, "", &mut manifest)?;
+        } else if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            let metadata = fs::symlink_metadata(path)?;
+            manifest_entry(&mut walk, path, &metadata, name, &mut manifest)?;
+        }
+    }
+    Ok(manifest)
+}
+
+fn manifest_dir(
+    walk: &mut FilterWalk,
+    path: &Path,
+    rel_dir: &str,
+    manifest: &mut String,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    walk.enter_dir(path, rel_dir)?;
+    let res = entries.into_iter().try_for_each(|entry| {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let rel_path = if rel_dir.is_empty() {
+            name
+        } else {
+            format!("{rel_dir}/{name}")
+        };
+        manifest_entry(walk, &entry.path(), &entry.metadata()?, &rel_path, manifest)
+    });
+    walk.leave_dir();
+    res
+}
+
+fn manifest_entry(
+    walk: &mut FilterWalk,
+    path: &Path,
+    metadata: &fs::Metadata,
+    rel_path: &str,
+    manifest: &mut String,
+) -> io::Result<()> {
+    let file_type = metadata.file_type();
+    if walk.is_excluded(rel_path, file_type.is_dir()) {
+        return Ok(());
+    }
+    writeln!(
+        manifest,
+        "{rel_path} {} {}.{:09}",
+        metadata.len(),
+        metadata.mtime(),
+        metadata.mtime_nsec()
+    )
+    .unwrap();
+    if file_type.is_dir() {
+        manifest_dir(walk, path, rel_path, manifest)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use anyhow::Context;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use crate::exec::{RedoxerExecConfig, RedoxerImage};
+use crate::redoxfs::extract_path;
+use crate::{gnu_target, redoxer_dir};
+
+pub(crate) const GDB_PORT: u16 = 1234;
+
+// newer kernel packages ship symbols next to the kernel
+const KERNEL_PATHS: &[&str] = &[
+    "usr/lib/boot/kernel.sym",
+    "usr/lib/boot/kernel",
+    "boot/kernel",
+];
+
+/// Write a gdb script loading the command and kernel symbols into `gdb_dir`,
+/// returns its path
+pub(crate) fn write_script(
+    config: &RedoxerExecConfig,
+    image: &RedoxerImage,
+    port: u16,
+    gdb_dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(gdb_dir).context("unable to create gdb dir")?;
+
+    let mut script = String::new();
+
+    // relibc and other libraries from the toolchain
+    let toolchain = env::var("REDOXER_TOOLCHAIN")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| redoxer_dir().join("toolchain"));
+    // the toolchain is laid out by gnu target, which differs for riscv64gc
+    let sysroot = toolchain.join(gnu_target());
+    if sysroot.is_dir() {
+        writeln!(script, "set sysroot {}", sysroot.display())?;
+    }
+
+    if let Some(program) = command_elf(config, image, gdb_dir)? {
+        writeln!(script, "file {}", program.display())?;
+    }
+
+    let kernel = gdb_dir.join("kernel");
+    for path in KERNEL_PATHS {
+        if extract_path(&image.disk, path, &kernel)? {
+            writeln!(script, "add-symbol-file {}", kernel.display())?;
+            break;
+        }
+    }
+
+    writeln!(script, "target remote :{port}")?;
+
+    let script_path = gdb_dir.join("redoxer.gdb");
+    fs::write(&script_path, script).context("unable to write gdb script")?;
+    Ok(script_path)
+}
+
+/// The host ELF of the command, or a copy of it from the image
+fn command_elf(
+    config: &RedoxerExecConfig,
+    image: &RedoxerImage,
+    gdb_dir: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let Some(cmd) = config.arguments.first() else {
+        return Ok(None);
+    };
+    if Path::new(cmd).is_file() {
+        return Ok(Some(fs::canonicalize(cmd)?));
+    }
+
+    let guest_path = if cmd.starts_with('/') {
+        cmd.clone()
+    } else {
+        format!("/usr/bin/{cmd}")
+    };
+    let program = gdb_dir.join("program");
+    if extract_path(&image.disk, &guest_path, &program)? {
+        Ok(Some(program))
+    } else {
+        Ok(None)
+    }
+}
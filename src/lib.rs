@@ -1,16 +1,48 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{env::VarError, io, path, process};
 
 pub(crate) use self::toolchain::toolchain;
 
+/// eprintln for progress messages, hidden when stderr belongs to the guest command
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if !$crate::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+#[cfg(feature = "cli-exec")]
+mod cache;
 mod cargo;
+#[cfg(feature = "cli-exec")]
+mod crash;
 mod env;
 #[cfg(feature = "cli-exec")]
 mod exec;
+#[cfg(feature = "cli-exec")]
+mod filter;
+#[cfg(feature = "cli-exec")]
+mod gdb;
+#[cfg(feature = "cli-exec")]
+mod output;
+#[cfg(feature = "cli-exec")]
+mod parallel;
 #[cfg(feature = "cli-pkg")]
 mod pkg;
 #[cfg(feature = "cli-exec")]
+mod profile;
+#[cfg(feature = "cli-exec")]
+mod qemu;
+#[cfg(feature = "cli-exec")]
 mod redoxfs;
+#[cfg(feature = "cli-exec")]
+mod report;
+#[cfg(feature = "cli-exec")]
+mod timings;
 mod toolchain;
+#[cfg(feature = "cli-exec")]
+mod vm;
 mod writer;
 
 const SUPPORTED_TARGETS: &[&str] = &[
@@ -23,6 +55,17 @@ const SUPPORTED_TARGETS: &[&str] = &[
     "aarch64-unknown-linux-relibc",
 ];
 
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "cli-exec")]
+pub(crate) fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub(crate) fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
 fn redoxer_dir() -> path::PathBuf {
     dirs::home_dir()
         .unwrap_or(path::PathBuf::from("."))
@@ -42,6 +85,7 @@ fn usage() {
     eprintln!("redoxer ar - run AR GNU compiler with Redox target");
     eprintln!("redoxer bench - cargo bench with Redox target in Redox VM");
     eprintln!("redoxer build - cargo build with Redox target");
+    eprintln!("redoxer cache - list, clean, prune or refresh cached images and toolchains");
     eprintln!("redoxer cc - run C GNU compiler with Redox target");
     eprintln!("redoxer check - cargo check with Redox target");
     eprintln!("redoxer clippy - cargo clippy with Redox target");
@@ -55,6 +99,7 @@ fn usage() {
     eprintln!("redoxer run - cargo run with Redox target in Redox VM");
     eprintln!("redoxer rustc - cargo rustc with Redox target");
     eprintln!("redoxer test - cargo test with Redox target in Redox VM");
+    eprintln!("redoxer vm - manage a persistent Redox VM reused by exec");
     eprintln!("redoxer write-exec - write redoxer exec test configuration");
     eprintln!("redoxer toolchain - install toolchain");
     eprintln!();
@@ -156,6 +201,10 @@ pub fn main(args: &[String]) {
             "fetch" | "install" | "run" | "rustc" | "test" => cargo::main(args),
             "ar" | "cc" | "cxx" | "env" => env::main(args),
             #[cfg(feature = "cli-exec")]
+            "cache" => cache::main(args),
+            #[cfg(not(feature = "cli-exec"))]
+            "cache" => panic!("feature 'cli-exec' is not compiled"),
+            #[cfg(feature = "cli-exec")]
             "exec" => exec::main(args),
             #[cfg(not(feature = "cli-exec"))]
             "exec" => panic!("feature 'cli-exec' is not compiled"),
@@ -164,6 +213,10 @@ pub fn main(args: &[String]) {
             #[cfg(not(feature = "cli-pkg"))]
             "pkg" => panic!("feature 'cli-pkg' is not compiled"),
             "toolchain" => toolchain::main(args),
+            #[cfg(feature = "cli-exec")]
+            "vm" => vm::main(args),
+            #[cfg(not(feature = "cli-exec"))]
+            "vm" => panic!("feature 'cli-exec' is not compiled"),
             "write-exec" => writer::main(args),
             _ => usage(),
         },
@@ -0,0 +1,251 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::Instant;
+
+const EXIT_STATUS_MARKER: &[u8] = b"## redoxer exit status ";
+
+/// command output written to the log by redoxer_exec.ion
+#[derive(Default)]
+pub(crate) struct GuestLog {
+    // log without the lines written by redoxer_exec.ion
+    pub(crate) log: Vec<u8>,
+    pub(crate) exit_status: Option<i32>,
+    pub(crate) stdout: Option<Vec<u8>>,
+    pub(crate) stderr: Option<Vec<u8>>,
+}
+
+impl GuestLog {
+    pub(crate) fn parse(log: &[u8]) -> Self {
+        let mut guest_log = GuestLog {
+            log: Vec::with_capacity(log.len()),
+            ..Default::default()
+        };
+        // base64 of the stream being read
+        let mut stream: Option<(bool, Vec<u8>)> = None;
+        for line in log.split_inclusive(|b| *b == b'\n') {
+            let trimmed = line.trim_ascii_end();
+            match trimmed {
+                b"## redoxer stdout ##" => stream = Some((false, Vec::new())),
+                b"## redoxer stderr ##" | b"## redoxer end ##" => {
+                    if let Some((is_stderr, data)) = stream.take() {
+                        if is_stderr {
+                            guest_log.stderr = Some(decode_base64(&data));
+                        } else {
+                            guest_log.stdout = Some(decode_base64(&data));
+                        }
+                    }
+                    if trimmed == b"## redoxer stderr ##" {
+                        stream = Some((true, Vec::new()));
+                    }
+                }
+                _ => {
+                    if let Some((_, data)) = stream.as_mut() {
+                        data.extend_from_slice(trimmed);
+                        continue;
+                    }
+                    let status = trimmed
+                        .strip_prefix(EXIT_STATUS_MARKER)
+                        .and_then(|s| s.strip_suffix(b" ##"))
+                        .and_then(|s| std::str::from_utf8(s).ok())
+                        .and_then(|s| s.parse::<i32>().ok());
+                    match status {
+                        Some(status) => guest_log.exit_status = Some(status),
+                        None => guest_log.log.extend_from_slice(line),
+                    }
+                }
+            }
+        }
+        guest_log
+    }
+}
+
+/// decode base64, skipping line breaks and padding
+fn decode_base64(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() / 4 * 3);
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for b in data {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => continue,
+        };
+        buf = (buf << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buf >> bits) as u8);
+        }
+    }
+    output
+}
+
+// printed by run_redoxer.ion right before redoxerd starts the command
+const RUNNING_MARKER: &[u8] = b"## running redoxer ##";
+
+/// remove ANSI escape sequences and turn CRLF into LF
+pub(crate) fn clean_line(line: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(line.len());
+    let mut i = 0;
+    while i < line.len() {
+        match line[i] {
+            0x1b => {
+                i += 1;
+                match line.get(i) {
+                    // CSI, ends with a byte in 0x40..=0x7e
+                    Some(b'[') => {
+                        i += 1;
+                        while i < line.len() && !(0x40..=0x7e).contains(&line[i]) {
+                            i += 1;
+                        }
+                    }
+                    // OSC, ends with BEL or ESC \
+                    Some(b']') => {
+                        i += 1;
+                        while i < line.len() && line[i] != 0x07 && line[i] != 0x1b {
+                            i += 1;
+                        }
+                        if line.get(i) == Some(&0x1b) {
+                            i += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            b'\r' if line.get(i + 1) == Some(&b'\n') => {}
+            b => output.push(b),
+        }
+        i += 1;
+    }
+    output
+}
+
+/// How the serial console of qemu is shown
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ConsoleMode {
+    // copied to stdout as it comes
+    Show,
+    // held back until the command runs
+    QuietBoot,
+    // only recorded
+    Hide,
+}
+
+/// Serial console of qemu, recorded for the crash analysis
+pub(crate) struct BootConsole {
+    boot: Vec<u8>,
+    serial: Vec<u8>,
+    // cleaned lines from when the command started
+    command: Vec<u8>,
+    // when the start and exit status markers were read
+    started: Option<Instant>,
+    finished: Option<Instant>,
+}
+
+impl BootConsole {
+    /// read the console until qemu exits, returns the boot transcript
+    pub(crate) fn run<R: Read>(console: R, mode: ConsoleMode) -> io::Result<Self> {
+        let mut console_log = Self {
+            boot: Vec::new(),
+            serial: Vec::new(),
+            command: Vec::new(),
+            started: None,
+            finished: None,
+        };
+        let mut stdout = io::stdout();
+        let mut reader = BufReader::new(console);
+        if mode == ConsoleMode::Show {
+            // prompts without a line break still show up right away
+            let mut buf = [0; 4096];
+            // start of the line not checked for markers yet
+            let mut checked = 0;
+            loop {
+                let count = reader.read(&mut buf)?;
+                if count == 0 {
+                    break;
+                }
+                stdout.write_all(&buf[..count])?;
+                stdout.flush()?;
+                console_log.serial.extend_from_slice(&buf[..count]);
+                while let Some(end) = console_log.serial[checked..]
+                    .iter()
+                    .position(|b| *b == b'\n')
+                {
+                    let line = clean_line(&console_log.serial[checked..checked + end + 1]);
+                    console_log.add_line(line);
+                    checked += end + 1;
+                }
+            }
+            return Ok(console_log);
+        }
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            console_log.serial.extend_from_slice(&line);
+            let line = clean_line(&line);
+            if console_log.started.is_some()
+                && mode == ConsoleMode::QuietBoot
+                && !line.starts_with(EXIT_STATUS_MARKER)
+            {
+                stdout.write_all(&line)?;
+                stdout.flush()?;
+            }
+            console_log.add_line(line);
+        }
+        Ok(console_log)
+    }
+
+    /// keep a cleaned line of the boot, noting when the markers show up
+    fn add_line(&mut self, line: Vec<u8>) {
+        if self.started.is_none() {
+            if line.trim_ascii() == RUNNING_MARKER {
+                self.started = Some(Instant::now());
+            }
+            self.boot.extend_from_slice(&line);
+        } else {
+            if self.finished.is_none() && line.starts_with(EXIT_STATUS_MARKER) {
+                self.finished = Some(Instant::now());
+            }
+            self.command.extend_from_slice(&line);
+        }
+    }
+
+    /// serial console until qemu exited, as written by the guest
+    pub(crate) fn serial(&self) -> &[u8] {
+        &self.serial
+    }
+
+    /// serial console from when the command started, without escape codes,
+    /// the log of the command on targets without a debugcon device
+    pub(crate) fn command(&self) -> &[u8] {
+        &self.command
+    }
+
+    /// serial console until the command started
+    pub(crate) fn boot(&self) -> &[u8] {
+        &self.boot
+    }
+
+    /// when the command started, as seen on the serial console
+    pub(crate) fn started(&self) -> Option<Instant> {
+        self.started
+    }
+
+    /// when the command printed its exit status, as seen on the serial console
+    pub(crate) fn finished(&self) -> Option<Instant> {
+        self.finished
+    }
+
+    /// print the boot transcript, used when the run failed
+    pub(crate) fn print_boot(&self) -> io::Result<()> {
+        let mut stderr = io::stderr();
+        writeln!(stderr, "## redoxer boot transcript ##")?;
+        stderr.write_all(&self.boot)?;
+        writeln!(stderr, "## redoxer boot transcript end ##")
+    }
+}
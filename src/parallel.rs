@@ -0,0 +1,320 @@
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::{env, fs, thread};
+
+use crate::exec::{prepare_base, RedoxerExecConfig};
+use crate::report::{self, suite_name, Outcome, Report, ReportFormat, TestSuite};
+use crate::{status_error, target};
+
+/// A test binary built by `cargo test --no-run`
+struct TestBinary {
+    // file name without the hash, prefixed to its output
+    name: String,
+    path: PathBuf,
+    // cargo runs test binaries from their package directory
+    package_dir: PathBuf,
+    // exact names of the tests to run with --rerun-failed, or all tests
+    filters: Vec<String>,
+}
+
+// written next to the test binaries, read by --rerun-failed
+const LAST_RUN: &str = "redoxer-last-run.json";
+
+/// Build the test binaries and run each in its own vm, up to `jobs` at once,
+/// then write the reports of all of them and record the failures.
+/// `arguments` are cargo arguments, then `--` and the test binary arguments.
+pub(crate) fn test(
+    config: &RedoxerExecConfig,
+    jobs: usize,
+    arguments: &[String],
+) -> anyhow::Result<i32> {
+    if config.gdb.is_some() || config.stdin {
+        bail!("--gdb and --stdin cannot be used with --jobs, --report or --rerun-failed");
+    }
+
+    let (cargo_args, test_args) = match arguments.iter().position(|arg| arg == "--") {
+        Some(i) => (&arguments[..i], &arguments[i + 1..]),
+        None => (arguments, &[][..]),
+    };
+    let mut binaries = test_binaries(cargo_args)?;
+    let Some(last_run) = binaries.first().and_then(|binary| {
+        // target/$TARGET/$PROFILE, above the deps directory
+        Some(binary.path.parent()?.parent()?.join(LAST_RUN))
+    }) else {
+        return Ok(0);
+    };
+
+    if config.rerun_failed {
+        let data = fs::read(&last_run).with_context(|| {
+            format!(
+                "no test run recorded in {}, run redoxer test with --jobs or --report first",
+                last_run.display()
+            )
+        })?;
+        let failed = report::parse("", &data);
+        binaries.retain_mut(|binary| {
+            let mut found = false;
+            let mut whole = false;
+            for suite in failed.iter().filter(|suite| suite.name == binary.name) {
+                for case in suite.cases.iter() {
+                    if case.outcome == Outcome::Failed {
+                        found = true;
+                        // failed outside of the tests, run all of them
+                        whole |= case.name == "redoxer";
+                        binary.filters.push(case.name.clone());
+                    }
+                }
+            }
+            if whole {
+                binary.filters.clear();
+            }
+            found
+        });
+        if binaries.is_empty() {
+            eprintln!("redoxer: no failed tests in the last run");
+            return Ok(0);
+        }
+    }
+
+    // every vm would build a missing base image at the same time
+    prepare_base(config)?;
+
+    let jobs = jobs.min(binaries.len());
+    eprintln!(
+        "redoxer: running {} test binaries in up to {jobs} vms",
+        binaries.len()
+    );
+
+    // each run writes a json report, merged into the requested ones
+    let report_dir = tempfile::tempdir().context("unable to create report dir")?;
+    let report_path = |i: usize| report_dir.path().join(format!("{i}.json"));
+
+    let queue = Mutex::new(binaries.iter().enumerate().collect::<VecDeque<_>>());
+    let codes = Mutex::new(vec![None; binaries.len()]);
+    thread::scope(|scope| {
+        for slot in 0..jobs {
+            let (queue, codes) = (&queue, &codes);
+            scope.spawn(move || {
+                while let Some((i, binary)) = queue.lock().unwrap().pop_front() {
+                    let report = report_path(i);
+                    let code = run_binary(config, jobs, slot, binary, test_args, &report)
+                        .unwrap_or_else(|err| {
+                            eprintln!("[{}] redoxer: {err:#}", binary.name);
+                            3
+                        });
+                    codes.lock().unwrap()[i] = Some(code);
+                }
+            });
+        }
+    });
+
+    let codes: Vec<i32> = codes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|code| code.unwrap_or(3))
+        .collect();
+
+    let mut suites = Vec::new();
+    for (i, (binary, code)) in binaries.iter().zip(codes.iter()).enumerate() {
+        let data = fs::read(report_path(i)).unwrap_or_default();
+        let mut binary_suites = report::parse("", &data);
+        binary_suites.retain(|suite| !suite.name.is_empty());
+        if binary_suites.is_empty() {
+            let mut suite = TestSuite {
+                name: String::new(),
+                cases: Vec::new(),
+            };
+            suite.add_failure(&format!("redoxer exec failed, exit status {code}"));
+            binary_suites.push(suite);
+        }
+        for mut suite in binary_suites {
+            suite.name = binary.name.clone();
+            suites.push(suite);
+        }
+    }
+    report::write(&config.reports, &suites)?;
+    let record = Report {
+        format: ReportFormat::Json,
+        path: last_run.display().to_string(),
+    };
+    report::write(&[record], &suites)?;
+    report_dir.close()?;
+
+    // like cargo, the first failing binary decides the exit status
+    let mut result = 0;
+    eprintln!("## redoxer test summary ##");
+    for (binary, &code) in binaries.iter().zip(codes.iter()) {
+        let flaky = suites
+            .iter()
+            .filter(|suite| suite.name == binary.name)
+            .any(|suite| suite.cases.iter().any(|case| case.flaky));
+        if code == 0 && flaky {
+            eprintln!("{}: ok, flaky", binary.name);
+        } else if code == 0 {
+            eprintln!("{}: ok", binary.name);
+        } else {
+            eprintln!("{}: failed, exit status {code}", binary.name);
+            if result == 0 {
+                result = code;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Paths of the test binaries from the cargo json messages
+fn test_binaries(cargo_args: &[String]) -> anyhow::Result<Vec<TestBinary>> {
+    let output = crate::env::command("cargo")?
+        .arg("test")
+        .arg("--no-run")
+        .arg("--message-format=json-render-diagnostics")
+        .arg("--target")
+        .arg(target())
+        .args(cargo_args)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("unable to run cargo")?;
+    status_error(output.status).context("cargo test --no-run failed")?;
+
+    let mut binaries: Vec<TestBinary> = Vec::new();
+    for line in output.stdout.split(|b| *b == b'\n') {
+        let Ok(message) = serde_json::from_slice::<Value>(line) else {
+            continue;
+        };
+        // the target has a "test" key too, only the profile tells test builds apart
+        if message["reason"] != "compiler-artifact" || message["profile"]["test"] != true {
+            continue;
+        }
+        let Some(path) = message["executable"].as_str() else {
+            continue;
+        };
+        let mut name = suite_name(path);
+        let path = PathBuf::from(path);
+        if binaries.iter().any(|binary| binary.name == name) {
+            name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into();
+        }
+        let package_dir = message["manifest_path"]
+            .as_str()
+            .and_then(|manifest| Some(PathBuf::from(manifest).parent()?.to_path_buf()))
+            .unwrap_or_else(|| PathBuf::from("."));
+        binaries.push(TestBinary {
+            name,
+            path,
+            package_dir,
+            filters: Vec::new(),
+        });
+    }
+    Ok(binaries)
+}
+
+/// Run one test binary with `redoxer exec`, prefixing its output lines
+fn run_binary(
+    config: &RedoxerExecConfig,
+    jobs: usize,
+    slot: usize,
+    binary: &TestBinary,
+    test_args: &[String],
+    report: &Path,
+) -> anyhow::Result<i32> {
+    let mut config = config.clone();
+    config.reports = vec![Report {
+        format: ReportFormat::Json,
+        path: report.display().to_string(),
+    }];
+    config.rerun_failed = false;
+    // vms running at once cannot listen on the same host ports, each slot
+    // gets its own copy of the whole range of forwarded ports
+    let ports = config.forwards.iter().map(|forward| forward.host_port);
+    if let (Some(min), Some(max)) = (ports.clone().min(), ports.max())
+        && slot > 0
+    {
+        let offset = (usize::from(max - min) + 1) * slot;
+        for forward in config.forwards.iter_mut() {
+            forward.host_port = u16::try_from(usize::from(forward.host_port) + offset)
+                .context("forwarded port out of range")?;
+            eprintln!(
+                "[{}] redoxer: forwarding {} host port {} to guest port {}",
+                binary.name, forward.protocol, forward.host_port, forward.guest_port
+            );
+        }
+    }
+    if let Some(output) = config.output.as_mut()
+        && output != "-"
+        && jobs > 1
+    {
+        output.push('.');
+        output.push_str(&binary.name);
+    }
+    if let Some(status_file) = config.status_file.as_mut()
+        && jobs > 1
+    {
+        status_file.push('.');
+        status_file.push_str(&binary.name);
+    }
+    if config.cwd_from_host && config.cwd.is_none() {
+        config.cwd = config.guest_path(&binary.package_dir)?;
+    }
+    config.arguments = vec![binary.path.display().to_string()];
+    config.arguments.extend_from_slice(test_args);
+    if !binary.filters.is_empty() {
+        config.arguments.push("--exact".to_string());
+        config.arguments.extend(binary.filters.iter().cloned());
+    }
+
+    let mut child = Command::new(env::current_exe()?)
+        .arg("exec")
+        .args(config.to_args())
+        // the background vm runs one command at a time
+        .env("REDOXER_VM", "false")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("unable to spawn redoxer exec")?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    // one vm at a time does not need prefixes
+    let prefix = if jobs > 1 {
+        format!("[{}] ", binary.name)
+    } else {
+        String::new()
+    };
+    thread::scope(|scope| {
+        let stdout = scope.spawn(|| prefix_lines(&prefix, stdout, io::stdout()));
+        prefix_lines(&prefix, stderr, io::stderr())?;
+        stdout.join().unwrap()
+    })?;
+
+    let status = child.wait().context("unable to get redoxer exec status")?;
+    Ok(status.code().unwrap_or(3))
+}
+
+fn prefix_lines<R: Read, W: Write>(prefix: &str, reader: R, mut writer: W) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        // a single write keeps lines of different vms apart
+        let mut prefixed = prefix.as_bytes().to_vec();
+        prefixed.extend_from_slice(&line);
+        writer.write_all(&prefixed)?;
+        writer.flush()?;
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, bail, Context};
+use std::path::PathBuf;
+use std::{env, fs};
+
+use crate::exec::{qemu_disk_size, qemu_use_live_disk};
+use crate::target;
+
+// memory left to the guest besides a live disk, in MiB
+const LIVE_DISK_HEADROOM: u64 = 512;
+
+/// Hardware of the guest vm
+#[derive(Clone, PartialEq)]
+pub struct Profile {
+    // in MiB
+    pub memory: u64,
+    pub cpus: u32,
+    // machine type of the target when not set
+    pub machine: Option<String>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            memory: 2048,
+            cpus: 4,
+            machine: None,
+        }
+    }
+}
+
+/// Hardware given with --profile, --memory, --cpus and --machine
+#[derive(Clone, Default)]
+pub struct ProfileArgs {
+    pub name: Option<String>,
+    // in MiB
+    pub memory: Option<u64>,
+    pub cpus: Option<u32>,
+    pub machine: Option<String>,
+}
+
+impl ProfileArgs {
+    /// The profile of --profile or REDOXER_PROFILE, with the other flags
+    /// applied over it whatever their order
+    pub(crate) fn profile(&self) -> anyhow::Result<Profile> {
+        let mut profile = match self
+            .name
+            .clone()
+            .or_else(|| env::var("REDOXER_PROFILE").ok())
+        {
+            Some(name) => Profile::named(&name)?,
+            None => Profile::default(),
+        };
+        if let Some(memory) = self.memory {
+            profile.memory = memory;
+        }
+        if let Some(cpus) = self.cpus {
+            profile.cpus = cpus;
+        }
+        if self.machine.is_some() {
+            profile.machine.clone_from(&self.machine);
+        }
+        Ok(profile)
+    }
+}
+
+/// Profiles available without a profiles file
+fn builtin(name: &str) -> Option<Profile> {
+    let (memory, cpus) = match name {
+        "tiny" => (512, 1),
+        "default" => return Some(Profile::default()),
+        "big" => (8192, 8),
+        _ => return None,
+    };
+    Some(Profile {
+        memory,
+        cpus,
+        machine: None,
+    })
+}
+
+/// User profiles, a toml table per profile with memory, cpus and machine keys
+fn profiles_file() -> PathBuf {
+    match env::var("REDOXER_PROFILES") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => dirs::home_dir()
+            .unwrap_or(PathBuf::from("."))
+            .join(".redoxer")
+            .join("profiles.toml"),
+    }
+}
+
+impl Profile {
+    /// Find a profile in the profiles file, then in the built in ones
+    pub(crate) fn named(name: &str) -> anyhow::Result<Self> {
+        let path = profiles_file();
+        if path.is_file() {
+            let data = fs::read_to_string(&path)
+                .with_context(|| format!("unable to read {}", path.display()))?;
+            let table: toml::Table = toml::from_str(&data)
+                .with_context(|| format!("unable to parse {}", path.display()))?;
+            if let Some(profile) = table.get(name) {
+                return Self::from_toml(profile)
+                    .with_context(|| format!("invalid profile {name:?} in {}", path.display()));
+            }
+        }
+        builtin(name).ok_or_else(|| {
+            anyhow!(
+                "unknown profile {name:?}, expected tiny, default, big or a table in {}",
+                path.display()
+            )
+        })
+    }
+
+    fn from_toml(value: &toml::Value) -> anyhow::Result<Self> {
+        let Some(table) = value.as_table() else {
+            bail!("profile must be a table");
+        };
+        let mut profile = Profile::default();
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                ("memory", toml::Value::Integer(memory)) => {
+                    profile.memory = u64::try_from(*memory).context("memory must be positive")?
+                }
+                ("memory", toml::Value::String(memory)) => profile.memory = parse_memory(memory)?,
+                ("cpus", toml::Value::Integer(cpus)) => {
+                    profile.cpus = u32::try_from(*cpus).context("cpus must be positive")?
+                }
+                ("machine", toml::Value::String(machine)) => {
+                    profile.machine = Some(machine.clone())
+                }
+                _ => bail!("unexpected key {key:?}, expected memory, cpus or machine"),
+            }
+        }
+        Ok(profile)
+    }
+
+    /// Check that the target can boot with this hardware
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.memory == 0 {
+            bail!("a vm needs at least 1 MiB of memory");
+        }
+        if self.cpus == 0 {
+            bail!("a vm needs at least one cpu");
+        }
+        if qemu_use_live_disk() {
+            let disk = qemu_disk_size() / 1024 / 1024;
+            if self.memory < disk + LIVE_DISK_HEADROOM {
+                bail!(
+                    "{} MiB of memory is too small, the {disk} MiB live disk of {} is loaded into memory and needs at least {} MiB",
+                    self.memory,
+                    target(),
+                    disk + LIVE_DISK_HEADROOM
+                );
+            }
+        }
+        let is_32bit = matches!(target(), "i586-unknown-redox" | "i686-unknown-redox");
+        if is_32bit && self.memory > 4096 {
+            bail!(
+                "{} MiB of memory is too large, {} addresses at most 4096 MiB",
+                self.memory,
+                target()
+            );
+        }
+        if let Some(machine) = &self.machine {
+            let machines: &[&str] = match target() {
+                "i586-unknown-redox" | "i686-unknown-redox" | "x86_64-unknown-redox" => {
+                    &["q35", "pc"]
+                }
+                _ => &["virt"],
+            };
+            // versioned types like pc-q35-9.0 or virt-9.0 work as well
+            if !machines.iter().any(|m| machine.starts_with(m)) {
+                bail!(
+                    "machine {machine:?} is not supported for {}, expected {}",
+                    target(),
+                    machines.join(" or ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// parse sizes like "512", "512M" or "2G" into MiB
+pub(crate) fn parse_memory(memory: &str) -> anyhow::Result<u64> {
+    let split = memory
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(memory.len());
+    let (value, unit) = memory.split_at(split);
+    let Ok(value) = value.parse::<u64>() else {
+        bail!("invalid memory size {memory:?}");
+    };
+    let megabytes = match unit {
+        "" | "M" | "MiB" => 1,
+        "G" | "GiB" => 1024,
+        _ => bail!("invalid memory unit in {memory:?}, expected M or G"),
+    };
+    match value.checked_mul(megabytes) {
+        Some(value) => Ok(value),
+        None => bail!("memory size {memory:?} is too large"),
+    }
+}
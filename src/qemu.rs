@@ -0,0 +1,353 @@
+use anyhow::bail;
+
+// options without a value
+const FLAGS: &[&str] = &[
+    "-S",
+    "-s",
+    "-daemonize",
+    "-enable-kvm",
+    "-full-screen",
+    "-no-reboot",
+    "-no-shutdown",
+    "-no-user-config",
+    "-nodefaults",
+    "-nographic",
+    "-snapshot",
+];
+
+// options qemu accepts more than once, added to the defaults instead of replacing them,
+// -serial and -mon are not listed, a user console replaces the default one
+const REPEATABLE: &[&str] = &[
+    "-acpitable",
+    "-add-fd",
+    "-audiodev",
+    "-blockdev",
+    "-chardev",
+    "-device",
+    "-drive",
+    "-fsdev",
+    "-fw_cfg",
+    "-global",
+    "-netdev",
+    "-nic",
+    "-numa",
+    "-object",
+    "-parallel",
+    "-set",
+    "-smbios",
+    "-trace",
+    "-virtfs",
+];
+
+/// Split arguments like a shell, with single and double quotes and
+/// backslash escapes, but without expansions
+pub(crate) fn split_args(args: &str) -> anyhow::Result<Vec<String>> {
+    let mut split = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(arg) = arg.take() {
+                    split.push(arg);
+                }
+            }
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => bail!("unterminated single quote in {args:?}"),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // only these are escaped inside double quotes
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => bail!("unterminated double quote in {args:?}"),
+                        },
+                        Some(c) => arg.push(c),
+                        None => bail!("unterminated double quote in {args:?}"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => arg.get_or_insert_with(String::new).push(c),
+                None => bail!("trailing backslash in {args:?}"),
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    split.extend(arg);
+    Ok(split)
+}
+
+/// A qemu option with its value, or a disk image given without an option
+#[derive(Clone)]
+struct QemuArg {
+    option: String,
+    value: Option<String>,
+}
+
+impl QemuArg {
+    fn id(&self) -> Option<&str> {
+        self.value
+            .as_deref()?
+            .split(',')
+            .find_map(|part| part.strip_prefix("id="))
+    }
+
+    /// a pattern without a value matches every value, otherwise the value
+    /// must be the same or start with it followed by a comma
+    fn matches(&self, pattern: &QemuArg) -> bool {
+        if self.option != pattern.option {
+            return false;
+        }
+        match (&self.value, &pattern.value) {
+            (_, None) => true,
+            (Some(value), Some(pattern)) => {
+                value == pattern
+                    || value
+                        .strip_prefix(pattern.as_str())
+                        .is_some_and(|rest| rest.starts_with(','))
+            }
+            (None, Some(_)) => false,
+        }
+    }
+}
+
+/// Qemu arguments grouped into options and their values
+#[derive(Clone, Default)]
+pub(crate) struct QemuArgs(Vec<QemuArg>);
+
+impl QemuArgs {
+    pub(crate) fn parse<S: AsRef<str>>(args: &[S]) -> Self {
+        let mut parsed = Vec::new();
+        let mut args = args.iter().map(AsRef::as_ref).peekable();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') {
+                parsed.push(QemuArg {
+                    option: String::new(),
+                    value: Some(arg.to_string()),
+                });
+                continue;
+            }
+            // qemu accepts --option as well
+            let option = if arg.starts_with("--") {
+                arg[1..].to_string()
+            } else {
+                arg.to_string()
+            };
+            let value = if FLAGS.contains(&option.as_str()) {
+                None
+            } else {
+                args.next_if(|next| !next.starts_with('-'))
+                    .map(str::to_string)
+            };
+            parsed.push(QemuArg { option, value });
+        }
+        Self(parsed)
+    }
+
+    /// Add options that replace the same options, except repeatable ones,
+    /// which only replace an entry with the same id
+    pub(crate) fn merge(&mut self, other: QemuArgs) {
+        for arg in other.0.iter() {
+            if REPEATABLE.contains(&arg.option.as_str()) {
+                if let Some(id) = arg.id() {
+                    self.0
+                        .retain(|old| old.option != arg.option || old.id() != Some(id));
+                }
+            } else {
+                self.0.retain(|old| old.option != arg.option);
+            }
+        }
+        self.0.extend(other.0);
+    }
+
+    /// Add options, without replacing anything
+    pub(crate) fn append(&mut self, other: QemuArgs) {
+        self.0.extend(other.0);
+    }
+
+    /// Remove options matching the patterns, like `-vga` or `-device isa-debug-exit`
+    pub(crate) fn remove(&mut self, patterns: &QemuArgs) {
+        self.0
+            .retain(|arg| !patterns.0.iter().any(|pattern| arg.matches(pattern)));
+    }
+
+    pub(crate) fn into_args(self) -> Vec<String> {
+        let mut args = Vec::new();
+        for arg in self.0 {
+            if !arg.option.is_empty() {
+                args.push(arg.option);
+            }
+            args.extend(arg.value);
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: &[&str] = &[
+        "-m",
+        "2048",
+        "-device",
+        "isa-debug-exit,iobase=0xf4,iosize=0x04",
+        "-netdev",
+        "user,id=net0",
+        "-device",
+        "e1000,netdev=net0",
+        "-vga",
+        "std",
+        "-vga",
+        "none",
+    ];
+
+    fn merged(user: &str) -> Vec<String> {
+        let mut args = QemuArgs::parse(DEFAULT);
+        args.merge(QemuArgs::parse(&split_args(user).unwrap()));
+        args.into_args()
+    }
+
+    fn removed(patterns: &str) -> Vec<String> {
+        let mut args = QemuArgs::parse(DEFAULT);
+        args.remove(&QemuArgs::parse(&split_args(patterns).unwrap()));
+        args.into_args()
+    }
+
+    #[test]
+    fn user_device_keeps_isa_debug_exit() {
+        let args = merged("-device virtio-rng-pci -m 4096");
+        assert_eq!(
+            args,
+            [
+                "-device",
+                "isa-debug-exit,iobase=0xf4,iosize=0x04",
+                "-netdev",
+                "user,id=net0",
+                "-device",
+                "e1000,netdev=net0",
+                "-vga",
+                "std",
+                "-vga",
+                "none",
+                "-device",
+                "virtio-rng-pci",
+                "-m",
+                "4096",
+            ]
+        );
+    }
+
+    #[test]
+    fn netdev_with_same_id_replaces_default() {
+        let args = merged("-netdev user,id=net0,hostfwd=tcp::8080-:80 -netdev tap,id=net1");
+        let netdevs: Vec<&str> = args
+            .windows(2)
+            .filter(|pair| pair[0] == "-netdev")
+            .map(|pair| pair[1].as_str())
+            .collect();
+        assert_eq!(
+            netdevs,
+            ["user,id=net0,hostfwd=tcp::8080-:80", "tap,id=net1"]
+        );
+        assert!(args.iter().any(|arg| arg == "e1000,netdev=net0"));
+    }
+
+    #[test]
+    fn serial_and_mon_replace_default_console() {
+        let mut args = QemuArgs::parse(&[
+            "-chardev",
+            "stdio,id=debug,signal=off,mux=on",
+            "-serial",
+            "chardev:debug",
+            "-mon",
+            "chardev=debug",
+        ]);
+        args.merge(QemuArgs::parse(
+            &split_args("-serial file:serial.log -mon none").unwrap(),
+        ));
+        assert_eq!(
+            args.into_args(),
+            [
+                "-chardev",
+                "stdio,id=debug,signal=off,mux=on",
+                "-serial",
+                "file:serial.log",
+                "-mon",
+                "none",
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_without_value_removes_every_entry() {
+        let args = removed("-vga");
+        assert!(!args.iter().any(|arg| arg == "-vga"));
+        assert_eq!(args.iter().filter(|arg| *arg == "-device").count(), 2);
+    }
+
+    #[test]
+    fn remove_with_value_only_removes_matching_entries() {
+        let args = removed("-device isa-debug-exit -device e1000,netdev=net1 -vga none");
+        assert_eq!(
+            args,
+            [
+                "-m",
+                "2048",
+                "-netdev",
+                "user,id=net0",
+                "-device",
+                "e1000,netdev=net0",
+                "-vga",
+                "std",
+            ]
+        );
+        // a prefix of the value without a comma after it is a different device
+        let args = removed("-device isa-debug");
+        assert!(args.iter().any(|arg| arg.starts_with("isa-debug-exit")));
+    }
+
+    #[test]
+    fn quoted_paths_with_spaces() {
+        let args = split_args(
+            r#"-drive file="/tmp/my disk.img",format=raw -kernel '/opt/a b/kernel' -L /usr/share/my\ qemu -append "say \"hi\"""#,
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            [
+                "-drive",
+                "file=/tmp/my disk.img,format=raw",
+                "-kernel",
+                "/opt/a b/kernel",
+                "-L",
+                "/usr/share/my qemu",
+                "-append",
+                "say \"hi\"",
+            ]
+        );
+        let mut parsed = QemuArgs::parse(DEFAULT);
+        parsed.merge(QemuArgs::parse(&args));
+        assert!(parsed
+            .into_args()
+            .iter()
+            .any(|arg| arg == "file=/tmp/my disk.img,format=raw"));
+        assert!(split_args("-kernel '/opt/a b").is_err());
+        assert!(split_args(r#"-kernel "/opt/a b"#).is_err());
+    }
+}
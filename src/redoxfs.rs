@@ -1,115 +1,24 @@
 use anyhow::{anyhow, bail, Context};
-use redoxfs::{BlockAddr, BlockMeta, DiskFile, FileSystem};
+use redoxfs::{BlockAddr, BlockMeta, DiskFile, FileSystem, Node, TreeData, TreePtr};
+use std::ffi::OsStr;
 use std::fs::File;
-use std::os::unix::fs::MetadataExt;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::mpsc::{channel, TryRecvError};
-use std::{fs, io, thread, time};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::{fs, io, thread};
 
+use crate::filter::{CopyFilter, FilterWalk};
 use crate::status_error;
 
-pub struct RedoxFs {
-    image: PathBuf,
-    dir: PathBuf,
-}
-
 pub(crate) fn syscall_error(err: syscall::Error) -> io::Error {
     io::Error::from_raw_os_error(err.errno)
 }
 
-impl RedoxFs {
-    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(image: P, dir: Q) -> io::Result<Self> {
-        let image = image.as_ref().to_owned();
-        let dir = fs::canonicalize(dir)?;
-        let mut s = Self { image, dir };
-        s.mount()?;
-        Ok(s)
-    }
-
-    pub fn mount(&mut self) -> io::Result<()> {
-        if self.mounted()? {
-            return Err(io::Error::other("directory was already mounted"));
-        }
-
-        let (tx, rx) = channel();
-
-        let disk = DiskFile::open(&self.image).map_err(syscall_error)?;
-        let fs = FileSystem::open(disk, None, None, true).map_err(syscall_error)?;
-        let dir = self.dir.clone();
-        thread::spawn(move || {
-            let _ = tx.send(redoxfs::mount(fs, dir, |_| {}));
-        });
-
-        while !self.mounted()? {
-            match rx.try_recv() {
-                Ok(res) => match res {
-                    Ok(()) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::NotConnected,
-                            "redoxfs thread exited early",
-                        ))
-                    }
-                    Err(err) => return Err(err),
-                },
-                Err(err) => match err {
-                    TryRecvError::Empty => (),
-                    TryRecvError::Disconnected => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::NotConnected,
-                            "redoxfs thread did not send a result",
-                        ))
-                    }
-                },
-            }
-            thread::sleep(time::Duration::from_millis(1));
-        }
-
-        Ok(())
-    }
-
-    pub fn unmount(&mut self) -> io::Result<()> {
-        if self.mounted()? {
-            Command::new("fusermount")
-                .arg("-u")
-                .arg(&self.dir)
-                .status()
-                .and_then(status_error)?;
-
-            if self.mounted()? {
-                return Err(io::Error::other("directory was still mounted"));
-            }
-        }
-
-        Ok(())
-    }
-
-    pub fn mounted(&self) -> io::Result<bool> {
-        use proc_mounts::MountIter;
-
-        for mount_res in MountIter::new()? {
-            let mount = mount_res?;
-            if mount.dest == self.dir {
-                return Ok(true);
-            }
-        }
-
-        Ok(false)
-    }
-}
-
-impl Drop for RedoxFs {
-    fn drop(&mut self) {
-        if let Err(err) = self.unmount() {
-            panic!(
-                "RedoxFs::drop: failed to unmount '{}': {}",
-                self.dir.display(),
-                err
-            );
-        }
-    }
-}
-
 pub fn run_install_mount(
     config: redox_installer::Config,
     bootloader_bin: &Path,
@@ -132,7 +41,7 @@ pub fn run_install_mount(
         redox_installer::with_redoxfs_mount(fs, Some(base_dir), move |base_dir| {
             if base_tar.exists() {
                 // redoxer in docker was built without FUSE, then CI has FUSE
-                eprintln!("redoxer: extracting archive");
+                progress!("redoxer: extracting archive");
                 extract_tar(base_tar, base_dir)?;
             } else {
                 run_install_to_dir(config, base_dir)?;
@@ -262,6 +171,372 @@ pub(crate) fn expand_disk(disk_path: &Path, desired_size: u64) -> anyhow::Result
     Ok(())
 }
 
+/// Copy `guest_path` in a redoxfs image to `host_path`, without mounting it,
+/// returns false if `guest_path` does not exist
+pub(crate) fn extract_path(
+    disk_path: &Path,
+    guest_path: &str,
+    host_path: &Path,
+) -> anyhow::Result<bool> {
+    let mut fs = open_fs(disk_path)?;
+
+    let mut node_ptr = TreePtr::root();
+    for name in guest_path.split('/').filter(|name| !name.is_empty()) {
+        node_ptr = match fs.tx(|tx| tx.find_node(node_ptr, name)) {
+            Ok(node) => node.ptr(),
+            Err(err) if err.errno == syscall::ENOENT => return Ok(false),
+            Err(err) => return Err(syscall_error(err).into()),
+        };
+    }
+
+    if let Some(parent) = host_path.parent()
+        && !parent.is_dir()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    extract_node(&mut fs, node_ptr, host_path)
+        .with_context(|| format!("unable to extract '/{guest_path}' from redoxfs"))?;
+    Ok(true)
+}
+
+fn extract_node(
+    fs: &mut FileSystem<DiskFile>,
+    node_ptr: TreePtr<Node>,
+    host_path: &Path,
+) -> anyhow::Result<()> {
+    let node = fs.tx(|tx| tx.read_tree(node_ptr)).map_err(syscall_error)?;
+
+    if node.data().is_dir() {
+        if !host_path.is_dir() {
+            fs::create_dir_all(host_path)?;
+        }
+        let mut children = Vec::new();
+        fs.tx(|tx| tx.child_nodes(node_ptr, &mut children))
+            .map_err(syscall_error)?;
+        for child in children {
+            match child.name() {
+                Some(".") | Some("..") | None => continue,
+                Some(name) => extract_node(fs, child.node_ptr(), &host_path.join(name))?,
+            }
+        }
+    } else if node.data().is_symlink() || node.data().is_file() {
+        // like rsync, replace what is already there
+        if host_path.symlink_metadata().is_ok() {
+            fs::remove_file(host_path)?;
+        }
+
+        let mut data = Vec::new();
+        let mut file = if node.data().is_file() {
+            Some(File::create(host_path)?)
+        } else {
+            None
+        };
+        let mut buf = vec![0; 1024 * 1024];
+        let mut offset = 0;
+        loop {
+            let count = fs
+                .tx(|tx| tx.read_node_inner(&node, offset, &mut buf))
+                .map_err(syscall_error)?;
+            if count == 0 {
+                break;
+            }
+            match file {
+                Some(ref mut file) => file.write_all(&buf[..count])?,
+                None => data.extend_from_slice(&buf[..count]),
+            }
+            offset += count as u64;
+        }
+
+        if file.is_none() {
+            symlink(OsStr::from_bytes(&data), host_path)?;
+            return Ok(());
+        }
+    } else {
+        // sockets are not copied
+        return Ok(());
+    }
+
+    let mode = u32::from(node.data().mode() & Node::MODE_PERM);
+    fs::set_permissions(host_path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+// files at least this large are read by worker threads while the image is written
+const PARALLEL_COPY_SIZE: u64 = 1024 * 1024;
+const COPY_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+struct FileJob {
+    path: PathBuf,
+    node_ptr: TreePtr<Node>,
+}
+
+struct FileChunk {
+    node_ptr: TreePtr<Node>,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// Copy host paths into a redoxfs image without mounting it, like `rsync --archive`
+///
+/// Each copy is a host path and a guest directory. A host directory ending
+/// with a slash is merged into the guest directory, any other path is copied
+/// into it under its own name. Paths excluded by `filter` are skipped.
+pub(crate) fn populate_image(
+    disk_path: &Path,
+    copies: &[(String, String)],
+    filter: &CopyFilter,
+) -> anyhow::Result<()> {
+    let mut fs = open_fs(disk_path)?;
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let (job_tx, job_rx) = channel();
+    let (chunk_tx, chunk_rx) = sync_channel(workers * 2);
+    let job_rx = Mutex::new(job_rx);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let chunk_tx = chunk_tx.clone();
+            let job_rx = &job_rx;
+            scope.spawn(move || read_files(job_rx, chunk_tx));
+        }
+        drop(chunk_tx);
+
+        let mut copier = ImageCopier {
+            fs: &mut fs,
+            jobs: job_tx,
+            filter: FilterWalk::new(filter),
+        };
+        let mut res = copies
+            .iter()
+            .try_for_each(|(host_path, guest_dir)| copier.copy(host_path, guest_dir));
+        let ImageCopier { fs, jobs, .. } = copier;
+        drop(jobs);
+
+        // keep draining after an error, so the workers can finish
+        for chunk in chunk_rx {
+            if res.is_ok() {
+                res = chunk.and_then(|chunk| {
+                    fs.tx(|tx| tx.write_node(chunk.node_ptr, chunk.offset, &chunk.data, 0, 0))
+                        .map_err(syscall_error)?;
+                    Ok(())
+                });
+            }
+        }
+        res
+    })
+}
+
+fn read_files(jobs: &Mutex<Receiver<FileJob>>, chunks: SyncSender<anyhow::Result<FileChunk>>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let res = read_file(&job, &chunks)
+            .with_context(|| format!("unable to copy '{}'", job.path.display()));
+        if let Err(err) = res
+            && chunks.send(Err(err)).is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn read_file(job: &FileJob, chunks: &SyncSender<anyhow::Result<FileChunk>>) -> anyhow::Result<()> {
+    let mut file = File::open(&job.path)?;
+    let mut offset = 0;
+    loop {
+        let mut data = Vec::with_capacity(COPY_CHUNK_SIZE);
+        let count = (&mut file)
+            .take(COPY_CHUNK_SIZE as u64)
+            .read_to_end(&mut data)?;
+        if count == 0 {
+            return Ok(());
+        }
+        let chunk = FileChunk {
+            node_ptr: job.node_ptr,
+            offset,
+            data,
+        };
+        if chunks.send(Ok(chunk)).is_err() {
+            return Ok(());
+        }
+        offset += count as u64;
+    }
+}
+
+struct ImageCopier<'a> {
+    fs: &'a mut FileSystem<DiskFile>,
+    jobs: Sender<FileJob>,
+    filter: FilterWalk,
+}
+
+impl ImageCopier<'_> {
+    fn copy(&mut self, host_path: &str, guest_dir: &str) -> anyhow::Result<()> {
+        let parent_ptr = self
+            .guest_dir(guest_dir)
+            .with_context(|| format!("unable to create '/{guest_dir}' in redoxfs"))?;
+
+        let path = Path::new(host_path);
+        if host_path.ends_with('/') && path.is_dir() {
+            return self.copy_dir(parent_ptr, path, "");
+        }
+
+        let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+            bail!("'{host_path}' has no valid file name");
+        };
+        let metadata = fs::symlink_metadata(path)?;
+        self.copy_entry(parent_ptr, name, path, &metadata, name)
+            .with_context(|| format!("unable to copy '{host_path}'"))
+    }
+
+    /// Find the guest directory, creating it when missing
+    fn guest_dir(&mut self, guest_dir: &str) -> anyhow::Result<TreePtr<Node>> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut node_ptr = TreePtr::root();
+        for name in guest_dir.split('/').filter(|name| !name.is_empty()) {
+            node_ptr = match self.fs.tx(|tx| tx.find_node(node_ptr, name)) {
+                Ok(node) if node.data().is_dir() => node.ptr(),
+                Ok(_) => bail!("'{name}' is not a directory"),
+                Err(err) if err.errno == syscall::ENOENT => self
+                    .fs
+                    .tx(|tx| {
+                        tx.create_node(
+                            node_ptr,
+                            name,
+                            Node::MODE_DIR | 0o755,
+                            now.as_secs(),
+                            now.subsec_nanos(),
+                        )
+                    })
+                    .map_err(syscall_error)?
+                    .ptr(),
+                Err(err) => return Err(syscall_error(err).into()),
+            };
+        }
+        Ok(node_ptr)
+    }
+
+    fn copy_dir(
+        &mut self,
+        node_ptr: TreePtr<Node>,
+        path: &Path,
+        rel_dir: &str,
+    ) -> anyhow::Result<()> {
+        let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        self.filter.enter_dir(path, rel_dir)?;
+        let res = entries.into_iter().try_for_each(|entry| {
+            let path = entry.path();
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                bail!("'{}' is not valid UTF-8", path.display());
+            };
+            let rel_path = if rel_dir.is_empty() {
+                name.clone()
+            } else {
+                format!("{rel_dir}/{name}")
+            };
+            let metadata = entry.metadata()?;
+            self.copy_entry(node_ptr, &name, &path, &metadata, &rel_path)
+                .with_context(|| format!("unable to copy '{}'", path.display()))
+        });
+        self.filter.leave_dir();
+        res
+    }
+
+    fn copy_entry(
+        &mut self,
+        parent_ptr: TreePtr<Node>,
+        name: &str,
+        path: &Path,
+        metadata: &fs::Metadata,
+        rel_path: &str,
+    ) -> anyhow::Result<()> {
+        let file_type = metadata.file_type();
+        if self.filter.is_excluded(rel_path, file_type.is_dir()) {
+            return Ok(());
+        }
+        let mode_type = if file_type.is_dir() {
+            Node::MODE_DIR
+        } else if file_type.is_file() {
+            Node::MODE_FILE
+        } else if file_type.is_symlink() {
+            Node::MODE_SYMLINK
+        } else {
+            progress!("redoxer: skipping special file '{}'", path.display());
+            return Ok(());
+        };
+        let mode = mode_type | (metadata.mode() as u16 & Node::MODE_PERM);
+
+        // like rsync, directories are merged and anything else is replaced
+        let existing = match self.fs.tx(|tx| tx.find_node(parent_ptr, name)) {
+            Ok(node) => Some(node),
+            Err(err) if err.errno == syscall::ENOENT => None,
+            Err(err) => return Err(syscall_error(err).into()),
+        };
+        let node = match existing {
+            Some(node) if node.data().is_dir() && file_type.is_dir() => node,
+            existing => self
+                .fs
+                .tx(|tx| {
+                    if let Some(node) = existing {
+                        tx.remove_node(parent_ptr, name, node.data().mode())?;
+                    }
+                    tx.create_node(
+                        parent_ptr,
+                        name,
+                        mode,
+                        metadata.ctime() as u64,
+                        metadata.ctime_nsec() as u32,
+                    )
+                })
+                .map_err(syscall_error)?,
+        };
+        let node_ptr = node.ptr();
+        self.fs
+            .tx(|tx| tx.sync_tree(node_attributes(node, mode, metadata)))
+            .map_err(syscall_error)?;
+
+        if file_type.is_dir() {
+            self.copy_dir(node_ptr, path, rel_path)
+        } else if file_type.is_symlink() {
+            let destination = fs::read_link(path)?;
+            self.write(node_ptr, destination.as_os_str().as_bytes())
+        } else if metadata.len() >= PARALLEL_COPY_SIZE {
+            let job = FileJob {
+                path: path.to_path_buf(),
+                node_ptr,
+            };
+            self.jobs
+                .send(job)
+                .map_err(|_| anyhow!("file copy workers exited early"))
+        } else {
+            self.write(node_ptr, &fs::read(path)?)
+        }
+    }
+
+    fn write(&mut self, node_ptr: TreePtr<Node>, data: &[u8]) -> anyhow::Result<()> {
+        if !data.is_empty() {
+            self.fs
+                .tx(|tx| tx.write_node(node_ptr, 0, data, 0, 0))
+                .map_err(syscall_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// mode, ownership and modification time of the host file
+fn node_attributes(mut node: TreeData<Node>, mode: u16, metadata: &fs::Metadata) -> TreeData<Node> {
+    let data = node.data_mut();
+    data.set_mode(mode);
+    data.set_uid(metadata.uid());
+    data.set_gid(metadata.gid());
+    data.set_mtime(metadata.mtime() as u64, metadata.mtime_nsec() as u32);
+    node
+}
+
 fn open_fs(disk_path: &Path) -> anyhow::Result<FileSystem<DiskFile>> {
     let disk = open_disk(disk_path)?;
     let fs = match FileSystem::open(disk, None, None, true) {
@@ -0,0 +1,535 @@
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::fmt::Write;
+use std::fs;
+
+use crate::output::clean_line;
+
+/// Format of a --report file
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Junit,
+    Json,
+}
+
+/// Test report written with --report
+#[derive(Clone)]
+pub struct Report {
+    pub format: ReportFormat,
+    pub path: String,
+}
+
+impl Report {
+    pub(crate) fn parse(report: &str) -> anyhow::Result<Self> {
+        let format = match report.split_once('=') {
+            Some(("junit", path)) if !path.is_empty() => ReportFormat::Junit,
+            Some(("json", path)) if !path.is_empty() => ReportFormat::Json,
+            _ => bail!("--report must be 'junit=path' or 'json=path'"),
+        };
+        Ok(Self {
+            format,
+            path: report.split_once('=').unwrap().1.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = match self.format {
+            ReportFormat::Junit => "junit",
+            ReportFormat::Json => "json",
+        };
+        write!(f, "{format}={}", self.path)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+pub(crate) struct TestCase {
+    pub(crate) name: String,
+    pub(crate) outcome: Outcome,
+    // seconds, only known with --report-time or --format json
+    pub(crate) time: Option<f64>,
+    pub(crate) output: String,
+    // failed on an earlier attempt of --retries
+    pub(crate) flaky: bool,
+}
+
+/// Test results of one test binary
+pub(crate) struct TestSuite {
+    pub(crate) name: String,
+    pub(crate) cases: Vec<TestCase>,
+}
+
+impl TestSuite {
+    /// add a failed test case for a run that failed outside of its tests,
+    /// like a boot failure or a kernel panic
+    pub(crate) fn add_failure(&mut self, message: &str) {
+        self.cases.push(TestCase {
+            name: "redoxer".to_string(),
+            outcome: Outcome::Failed,
+            time: None,
+            output: message.to_string(),
+            flaky: false,
+        });
+    }
+
+    pub(crate) fn has_failure(&self) -> bool {
+        self.cases
+            .iter()
+            .any(|case| case.outcome == Outcome::Failed)
+    }
+
+    fn count(&self, outcome: Outcome) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| case.outcome == outcome)
+            .count()
+    }
+
+    fn time(&self) -> f64 {
+        self.cases.iter().filter_map(|case| case.time).sum()
+    }
+}
+
+/// Name of a test binary without its directory and the hash added by cargo
+pub(crate) fn suite_name(path: &str) -> String {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    match file_name.rsplit_once('-') {
+        Some((name, hash)) if hash.chars().all(|c| c.is_ascii_hexdigit()) => name.to_string(),
+        _ => file_name.to_string(),
+    }
+}
+
+/// Parse libtest output, in the human format or with `--format json`.
+/// Suite events with a name start a new suite, like in the json reports.
+pub(crate) fn parse(name: &str, output: &[u8]) -> Vec<TestSuite> {
+    let mut suites = vec![TestSuite {
+        name: name.to_string(),
+        cases: Vec::new(),
+    }];
+    // test whose captured output is being read in the human format
+    let mut failure: Option<usize> = None;
+    for line in output.split_inclusive(|b| *b == b'\n') {
+        let line = String::from_utf8_lossy(&clean_line(line)).into_owned();
+        let line = line.trim_end_matches('\n');
+        let suite = suites.last_mut().unwrap();
+
+        // json events of libtest, other lines starting with a brace are output
+        if line.starts_with('{')
+            && let Ok(event) = serde_json::from_str::<Value>(line)
+        {
+            match event["type"].as_str() {
+                Some("suite") => {
+                    if let (Some("started"), Some(name)) =
+                        (event["event"].as_str(), event["name"].as_str())
+                    {
+                        suites.push(TestSuite {
+                            name: name.to_string(),
+                            cases: Vec::new(),
+                        });
+                    }
+                }
+                Some("test") => {
+                    let outcome = match event["event"].as_str() {
+                        Some("ok") => Outcome::Passed,
+                        Some("failed") => Outcome::Failed,
+                        Some("ignored") => Outcome::Ignored,
+                        _ => continue,
+                    };
+                    let Some(name) = event["name"].as_str() else {
+                        continue;
+                    };
+                    suite.cases.push(TestCase {
+                        name: name.to_string(),
+                        outcome,
+                        time: event["exec_time"].as_f64(),
+                        output: event["stdout"].as_str().unwrap_or_default().to_string(),
+                        flaky: event["flaky"].as_bool().unwrap_or(false),
+                    });
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        if let Some(name) = line
+            .strip_prefix("---- ")
+            .and_then(|line| line.strip_suffix(" stdout ----"))
+        {
+            // with --repeat, the output belongs to the latest run of the test
+            failure = suite.cases.iter().rposition(|case| case.name == name);
+            continue;
+        }
+        if let Some(i) = failure {
+            if line == "failures:" || line == "successes:" || line.starts_with("---- ") {
+                let output = &mut suite.cases[i].output;
+                output.truncate(output.trim_end().len());
+                failure = None;
+            } else {
+                let output = &mut suite.cases[i].output;
+                output.push_str(line);
+                output.push('\n');
+                continue;
+            }
+        }
+
+        // "test name ... ok", with " <0.001s>" when --report-time is used
+        let Some((name, result)) = line
+            .strip_prefix("test ")
+            .and_then(|line| line.split_once(" ... "))
+        else {
+            continue;
+        };
+        let (result, time) = match result.split_once(" <") {
+            Some((result, time)) => (
+                result,
+                time.strip_suffix("s>").and_then(|time| time.parse().ok()),
+            ),
+            None => (result, None),
+        };
+        let outcome = match result {
+            "ok" => Outcome::Passed,
+            "FAILED" => Outcome::Failed,
+            result if result.starts_with("ignored") => Outcome::Ignored,
+            _ => continue,
+        };
+        suite.cases.push(TestCase {
+            name: name.to_string(),
+            outcome,
+            time,
+            output: String::new(),
+            flaky: false,
+        });
+    }
+    if suites.len() > 1 && suites[0].cases.is_empty() {
+        suites.remove(0);
+    }
+    suites
+}
+
+/// Merge the runs of each test from --repeat into one case, failed when any
+/// run failed, with the output of that run and the time of all runs
+pub(crate) fn merge_repeats(suites: &mut [TestSuite]) {
+    for suite in suites {
+        let mut cases: Vec<TestCase> = Vec::new();
+        for case in suite.cases.drain(..) {
+            let Some(merged) = cases.iter_mut().find(|merged| merged.name == case.name) else {
+                cases.push(case);
+                continue;
+            };
+            merged.time = match (merged.time, case.time) {
+                (Some(merged), Some(time)) => Some(merged + time),
+                (merged, time) => merged.or(time),
+            };
+            if case.outcome == Outcome::Failed && merged.outcome != Outcome::Failed {
+                merged.outcome = Outcome::Failed;
+                merged.output = case.output;
+            }
+        }
+        suite.cases = cases;
+    }
+}
+
+/// Mark tests that failed on an earlier attempt and passed now as flaky,
+/// failures outside of the tests become a flaky test named redoxer
+pub(crate) fn mark_flaky(suites: &mut [TestSuite], failures: &[TestSuite]) {
+    for failure in failures {
+        let Some(suite) = suites.iter_mut().find(|suite| suite.name == failure.name) else {
+            continue;
+        };
+        for failed in failure.cases.iter() {
+            if failed.outcome != Outcome::Failed {
+                continue;
+            }
+            if let Some(case) = suite.cases.iter_mut().find(|case| case.name == failed.name) {
+                case.flaky |= case.outcome == Outcome::Passed;
+            } else if !suite.has_failure() {
+                suite.cases.push(TestCase {
+                    name: failed.name.clone(),
+                    outcome: Outcome::Passed,
+                    time: None,
+                    output: String::new(),
+                    flaky: true,
+                });
+            }
+        }
+    }
+}
+
+/// Write the reports for the suites of all test binaries
+pub(crate) fn write(reports: &[Report], suites: &[TestSuite]) -> anyhow::Result<()> {
+    for report in reports {
+        let data = match report.format {
+            ReportFormat::Junit => junit(suites),
+            ReportFormat::Json => json(suites),
+        };
+        fs::write(&report.path, data)
+            .with_context(|| format!("unable to write report {:?}", report.path))?;
+    }
+    Ok(())
+}
+
+/// libtest json events, with the suite names added
+fn json(suites: &[TestSuite]) -> String {
+    let mut json = String::new();
+    for suite in suites {
+        let _ = writeln!(
+            json,
+            r#"{{ "type": "suite", "event": "started", "name": {}, "test_count": {} }}"#,
+            json_escape(&suite.name),
+            suite.cases.len()
+        );
+        for case in suite.cases.iter() {
+            let event = match case.outcome {
+                Outcome::Passed => "ok",
+                Outcome::Failed => "failed",
+                Outcome::Ignored => "ignored",
+            };
+            let _ = write!(
+                json,
+                r#"{{ "type": "test", "name": {}, "event": "{event}""#,
+                json_escape(&case.name)
+            );
+            if let Some(time) = case.time {
+                let _ = write!(json, r#", "exec_time": {time}"#);
+            }
+            if case.flaky {
+                json.push_str(r#", "flaky": true"#);
+            }
+            if !case.output.is_empty() {
+                let _ = write!(json, r#", "stdout": {}"#, json_escape(&case.output));
+            }
+            json.push_str(" }\n");
+        }
+        let _ = writeln!(
+            json,
+            r#"{{ "type": "suite", "event": "{}", "name": {}, "passed": {}, "failed": {}, "ignored": {}, "exec_time": {} }}"#,
+            if suite.has_failure() { "failed" } else { "ok" },
+            json_escape(&suite.name),
+            suite.count(Outcome::Passed),
+            suite.count(Outcome::Failed),
+            suite.count(Outcome::Ignored),
+            suite.time()
+        );
+    }
+    json
+}
+
+fn junit(suites: &[TestSuite]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let tests: usize = suites.iter().map(|suite| suite.cases.len()).sum();
+    let failures: usize = suites
+        .iter()
+        .map(|suite| suite.count(Outcome::Failed))
+        .sum();
+    let _ = writeln!(
+        xml,
+        r#"<testsuites name="redoxer" tests="{tests}" failures="{failures}">"#
+    );
+    for suite in suites {
+        let _ = writeln!(
+            xml,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" time="{}">"#,
+            xml_escape(&suite.name),
+            suite.cases.len(),
+            suite.count(Outcome::Failed),
+            suite.count(Outcome::Ignored),
+            suite.time()
+        );
+        for case in suite.cases.iter() {
+            let _ = write!(
+                xml,
+                r#"    <testcase classname="{}" name="{}" time="{}""#,
+                xml_escape(&suite.name),
+                xml_escape(&case.name),
+                case.time.unwrap_or_default()
+            );
+            match case.outcome {
+                Outcome::Passed if case.output.is_empty() && !case.flaky => xml.push_str("/>\n"),
+                Outcome::Passed => {
+                    xml.push_str(">\n");
+                    if case.flaky {
+                        xml.push_str(
+                            "      <flakyFailure message=\"failed on an earlier attempt\"/>\n",
+                        );
+                    }
+                    if !case.output.is_empty() {
+                        let _ = writeln!(
+                            xml,
+                            "      <system-out>{}</system-out>",
+                            xml_escape(&case.output)
+                        );
+                    }
+                    xml.push_str("    </testcase>\n");
+                }
+                Outcome::Failed => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n      <failure message=\"failed\">{}</failure>\n    </testcase>",
+                        xml_escape(&case.output)
+                    );
+                }
+                Outcome::Ignored => xml.push_str(">\n      <skipped/>\n    </testcase>\n"),
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // not allowed in xml 1.0
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_escape(text: &str) -> String {
+    Value::from(text).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcomes(suite: &TestSuite) -> Vec<(&str, Outcome)> {
+        suite
+            .cases
+            .iter()
+            .map(|case| (case.name.as_str(), case.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn parse_human() {
+        let output = b"\
+running 3 tests\r
+test tests::ignored ... ignored, slow\r
+test tests::passes ... ok <0.250s>\r
+test tests::fails ... FAILED\r
+\r
+failures:\r
+\r
+---- tests::fails stdout ----\r
+{ not json, printed by the test }\r
+thread 'tests::fails' panicked at src/lib.rs:10:5:\r
+boom\r
+\r
+\r
+failures:\r
+    tests::fails\r
+\r
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out\r
+";
+        let suites = parse("crate", output);
+        assert_eq!(suites.len(), 1);
+        assert_eq!(suites[0].name, "crate");
+        assert_eq!(
+            outcomes(&suites[0]),
+            [
+                ("tests::ignored", Outcome::Ignored),
+                ("tests::passes", Outcome::Passed),
+                ("tests::fails", Outcome::Failed),
+            ]
+        );
+        assert_eq!(suites[0].cases[1].time, Some(0.25));
+        assert_eq!(
+            suites[0].cases[2].output,
+            "{ not json, printed by the test }\n\
+             thread 'tests::fails' panicked at src/lib.rs:10:5:\n\
+             boom"
+        );
+    }
+
+    #[test]
+    fn parse_json() {
+        let output = br#"{ "type": "suite", "event": "started", "test_count": 3 }
+{ "type": "test", "event": "started", "name": "tests::passes" }
+{ "type": "test", "name": "tests::passes", "event": "ok", "exec_time": 0.5 }
+{ "type": "test", "name": "tests::fails", "event": "failed", "stdout": "a \"quoted\" \u00e9\nline\n" }
+{ "type": "test", "name": "tests::ignored", "event": "ignored" }
+{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1, "measured": 0, "filtered_out": 0, "exec_time": 0.5 }
+"#;
+        let suites = parse("crate", output);
+        assert_eq!(suites.len(), 1);
+        assert_eq!(suites[0].name, "crate");
+        assert_eq!(
+            outcomes(&suites[0]),
+            [
+                ("tests::passes", Outcome::Passed),
+                ("tests::fails", Outcome::Failed),
+                ("tests::ignored", Outcome::Ignored),
+            ]
+        );
+        assert_eq!(suites[0].cases[0].time, Some(0.5));
+        assert_eq!(suites[0].cases[1].output, "a \"quoted\" \u{e9}\nline\n");
+    }
+
+    #[test]
+    fn merge_repeated_runs() {
+        let output = b"\
+## redoxer repeat 1/2 ##
+running 2 tests
+test tests::flaky ... ok <0.5s>
+test tests::passes ... ok <1s>
+## redoxer repeat 2/2 ##
+running 2 tests
+test tests::flaky ... FAILED <0.25s>
+test tests::passes ... ok <1s>
+
+failures:
+
+---- tests::flaky stdout ----
+second run
+
+failures:
+    tests::flaky
+";
+        let mut suites = parse("crate", output);
+        assert_eq!(suites[0].cases.len(), 4);
+        merge_repeats(&mut suites);
+        assert_eq!(
+            outcomes(&suites[0]),
+            [
+                ("tests::flaky", Outcome::Failed),
+                ("tests::passes", Outcome::Passed),
+            ]
+        );
+        assert_eq!(suites[0].cases[0].output, "second run");
+        assert_eq!(suites[0].cases[0].time, Some(0.75));
+        assert_eq!(suites[0].cases[1].time, Some(2.0));
+    }
+
+    #[test]
+    fn parse_written_json() {
+        let mut suite = TestSuite {
+            name: "with \"quotes\"".to_string(),
+            cases: Vec::new(),
+        };
+        suite.add_failure("line\n\ttab");
+        suite.cases[0].flaky = true;
+        let suites = parse("", json(&[suite]).as_bytes());
+        assert_eq!(suites.len(), 1);
+        assert_eq!(suites[0].name, "with \"quotes\"");
+        assert_eq!(outcomes(&suites[0]), [("redoxer", Outcome::Failed)]);
+        assert_eq!(suites[0].cases[0].output, "line\n\ttab");
+        assert!(suites[0].cases[0].flaky);
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// phases in the order they first finished, repeated phases add up
+static PHASES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+/// Add the duration of a phase
+pub(crate) fn record(name: &'static str, duration: Duration) {
+    let mut phases = PHASES.lock().unwrap();
+    match phases.iter_mut().find(|(phase, _)| *phase == name) {
+        Some((_, total)) => *total += duration,
+        None => phases.push((name, duration)),
+    }
+}
+
+/// Timer recording a phase when dropped
+pub(crate) struct Phase {
+    name: &'static str,
+    start: Instant,
+}
+
+pub(crate) fn phase(name: &'static str) -> Phase {
+    Phase {
+        name,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        record(self.name, self.start.elapsed());
+    }
+}
+
+/// Boot, command and shutdown phases of qemu, from the time qemu was spawned,
+/// the times the guest printed the start and exit status markers, and the
+/// time qemu exited
+pub(crate) fn record_qemu(
+    spawned: Instant,
+    started: Option<Instant>,
+    finished: Option<Instant>,
+    exited: Instant,
+) {
+    match (started, finished) {
+        (Some(started), Some(finished)) => {
+            record("boot", started - spawned);
+            record("command", finished - started);
+            record("shutdown", exited - finished);
+        }
+        // the command did not report its exit status
+        (Some(started), None) => {
+            record("boot", started - spawned);
+            record("command", exited - started);
+        }
+        // the command never started
+        (None, _) => record("boot", exited - spawned),
+    }
+}
+
+/// Print the phases and their total to stderr
+pub(crate) fn print() {
+    let phases = PHASES.lock().unwrap();
+    let total: Duration = phases.iter().map(|(_, duration)| *duration).sum();
+    eprintln!("## redoxer timings ##");
+    for (name, duration) in phases.iter() {
+        eprintln!("{name:<16}{:>10.2}s", duration.as_secs_f64());
+    }
+    eprintln!("{:<16}{:>10.2}s", "total", total.as_secs_f64());
+    eprintln!("## redoxer timings end ##");
+}
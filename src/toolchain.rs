@@ -94,7 +94,7 @@ fn check_shasum(shasum: &Vec<(String, PathBuf)>) -> io::Result<bool> {
 }
 
 //TODO: Rewrite with hyper or reqwest, tar-rs, sha2, and some gzip crate?
-fn toolchain_inner(is_update: bool, source_url: String) -> io::Result<PathBuf> {
+pub(crate) fn toolchain_inner(is_update: bool, source_url: String) -> io::Result<PathBuf> {
     if let Ok(redoxer_toolchain) = env::var("REDOXER_TOOLCHAIN") {
         return Ok(PathBuf::from(redoxer_toolchain));
     }
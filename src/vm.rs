@@ -0,0 +1,469 @@
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::{fs, io, thread, time};
+
+use crate::cargo::project_rules;
+use crate::exec::{self, RedoxerExecConfig, RunStatus};
+use crate::filter::manifest;
+use crate::writer::{guest_arguments, ExecWrapper};
+use crate::{parse_bool_env, redoxer_dir, status_error};
+
+const READY_MARKER: &str = "## redoxer vm ready ##";
+const JOB_MARKER: &str = "## redoxer vm job ##";
+const EXIT_MARKER: &str = "## redoxer vm exit ";
+
+// the guest needs to boot before accepting jobs
+const BOOT_TIMEOUT: time::Duration = time::Duration::from_secs(300);
+// an empty job lock older than this was left by a killed process
+const LOCK_WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+fn vm_dir() -> PathBuf {
+    redoxer_dir().join("vm")
+}
+
+/// folders, hardware, qemu arguments and install-config the vm was started with
+fn vm_key(config: &RedoxerExecConfig) -> String {
+    let mut key: Vec<String> = config
+        .folders
+        .iter()
+        .map(|(sysroot, host_dir)| format!("--folder {host_dir}:/{sysroot}"))
+        .collect();
+    key.sort();
+    for (include, pattern) in &config.filter.rules {
+        let flag = if *include { "--include" } else { "--exclude" };
+        key.push(format!("{flag} {pattern}"));
+    }
+    if config.filter.gitignore {
+        key.push("--gitignore".to_string());
+    }
+    key.push(format!(
+        "--memory {}M --cpus {}",
+        config.profile.memory, config.profile.cpus
+    ));
+    if let Some(machine) = &config.profile.machine {
+        key.push(format!("--machine {machine}"));
+    }
+    key.push(format!("--network {}", config.network));
+    for forward in &config.forwards {
+        key.push(format!("--forward {forward}"));
+    }
+    for (name, value) in [
+        ("REDOXER_QEMU_BINARY", &config.qemu_binary),
+        ("REDOXER_QEMU_ARGS", &config.qemu_args),
+        ("REDOXER_QEMU_ARGS_APPEND", &config.qemu_args_append),
+        ("REDOXER_QEMU_ARGS_REMOVE", &config.qemu_args_remove),
+    ] {
+        if let Some(value) = value {
+            key.push(format!("{name}={value}"));
+        }
+    }
+    // an install-config may be edited without renaming it
+    let config_hash = Sha256::digest(config.config_toml.as_bytes());
+    key.push(format!(
+        "--install-config {} {config_hash:x}",
+        config.config_name
+    ));
+    key.join("\n")
+}
+
+fn running_pid() -> io::Result<Option<u32>> {
+    let pid = match fs::read_to_string(vm_dir().join("qemu.pid")) {
+        Ok(pid) => pid,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let Ok(pid) = pid.trim().parse::<u32>() else {
+        return Ok(None);
+    };
+    Ok(is_alive(pid)?.then_some(pid))
+}
+
+fn is_alive(pid: u32) -> io::Result<bool> {
+    Ok(Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stderr(Stdio::null())
+        .status()?
+        .success())
+}
+
+/// Lock serializing jobs, the serial console only carries one at a time
+struct JobLock(PathBuf);
+
+impl JobLock {
+    fn acquire() -> io::Result<Self> {
+        let path = vm_dir().join("job.lock");
+        let mut waiting = false;
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    writeln!(file, "{}", process::id())?;
+                    return Ok(Self(path));
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    // an empty lock is still being written by its owner
+                    let owner = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|pid| pid.trim().parse::<u32>().ok());
+                    let stale = match owner {
+                        Some(owner) => !is_alive(owner)?,
+                        // killed between creating the lock and writing its pid
+                        None => fs::metadata(&path)
+                            .and_then(|m| m.modified())
+                            .ok()
+                            .and_then(|t| t.elapsed().ok())
+                            .is_some_and(|age| age > LOCK_WRITE_TIMEOUT),
+                    };
+                    if stale {
+                        // the owner was killed before it could remove the lock
+                        progress!("redoxer: removing vm lock of an exited process");
+                        match fs::remove_file(&path) {
+                            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                            _ => continue,
+                        }
+                    }
+                    if !waiting {
+                        match owner {
+                            Some(owner) => {
+                                progress!("redoxer: waiting for the vm job of process {owner}")
+                            }
+                            None => progress!("redoxer: waiting for the vm lock"),
+                        }
+                        waiting = true;
+                    }
+                    thread::sleep(time::Duration::from_millis(100));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for JobLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Quote arguments for `eval` in the guest agent
+fn job_line(arguments: &[String]) -> anyhow::Result<String> {
+    let mut line = String::new();
+    for arg in arguments {
+        if arg.contains(['\'', '\n', '\r']) {
+            bail!("argument {arg:?} cannot be passed to the vm, it contains a quote or newline");
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push('\'');
+        line.push_str(arg);
+        line.push('\'');
+    }
+    line.push('\n');
+    Ok(line)
+}
+
+/// Skip the files cargo commands skip when /root is a cargo project, the vm
+/// only matches `redoxer test` and the other cargo commands with the same rules
+fn add_project_rules(config: &mut RedoxerExecConfig) -> io::Result<()> {
+    if let Some(root) = config.folders.get("root") {
+        let root_dir = fs::canonicalize(root)?;
+        if root_dir.join("Cargo.toml").is_file() {
+            config.filter.rules.extend(project_rules(&root_dir));
+        }
+    }
+    Ok(())
+}
+
+fn start(config: &RedoxerExecConfig) -> anyhow::Result<()> {
+    if let Some(pid) = running_pid()? {
+        bail!("vm is already running with pid {pid}, stop it first");
+    }
+    if !config.arguments.is_empty() {
+        bail!("vm start does not take a command");
+    }
+    if !config.artifacts.is_empty() || config.gdb.is_some() {
+        bail!("vm start does not support --artifact or --gdb");
+    }
+
+    exec::check_requirements(config)?;
+
+    let vm_dir = vm_dir();
+    if vm_dir.is_dir() {
+        fs::remove_dir_all(&vm_dir).context("unable to remove old vm dir")?;
+    }
+    fs::create_dir_all(&vm_dir).context("unable to create vm dir")?;
+
+    eprintln!("redoxer: creating vm disk");
+    // taken before copying, a file changed during the copy boots a new vm later
+    fs::write(vm_dir.join("manifest"), folders_manifest(config)?)?;
+    let image = exec::prepare_image(config, &vm_dir, true)?;
+    fs::remove_dir_all(&image.dir).context("unable to remove vm staging dir")?;
+
+    let serial = vm_dir.join("serial.sock");
+    let mut command =
+        exec::qemu_command(config, &image, &vm_dir.join("redoxer.log"), Some(&serial))?;
+    command
+        .arg("-daemonize")
+        .arg("-pidfile")
+        .arg(vm_dir.join("qemu.pid"))
+        .arg("-monitor")
+        .arg(format!(
+            "unix:{},server=on,wait=off",
+            vm_dir.join("monitor.sock").display()
+        ));
+    if config.qemu_binary.is_some() {
+        eprintln!("{command:?}");
+    }
+    command
+        .status()
+        .and_then(status_error)
+        .context("unable to start qemu")?;
+
+    fs::write(vm_dir.join("config"), vm_key(config))?;
+
+    eprintln!("redoxer: waiting for vm to boot");
+    let stream = UnixStream::connect(&serial).context("unable to connect to vm serial")?;
+    stream.set_read_timeout(Some(BOOT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            bail!("vm serial closed before the guest was ready");
+        }
+        if String::from_utf8_lossy(&line).trim_end() == READY_MARKER {
+            break;
+        }
+    }
+
+    eprintln!(
+        "redoxer: vm started with pid {}",
+        running_pid()?.unwrap_or_default()
+    );
+    Ok(())
+}
+
+fn status() -> anyhow::Result<()> {
+    let Some(pid) = running_pid()? else {
+        println!("redoxer vm: stopped");
+        return Ok(());
+    };
+
+    let config_path = vm_dir().join("config");
+    let uptime = fs::metadata(&config_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    println!("redoxer vm: running with pid {pid} for {uptime}s");
+    for line in fs::read_to_string(&config_path)?.lines() {
+        println!("    {line}");
+    }
+    Ok(())
+}
+
+fn stop() -> anyhow::Result<()> {
+    if let Some(pid) = running_pid()? {
+        eprintln!("redoxer: stopping vm with pid {pid}");
+        if let Ok(mut monitor) = UnixStream::connect(vm_dir().join("monitor.sock")) {
+            let _ = monitor.write_all(b"quit\n");
+        }
+        let mut waited = 0;
+        while running_pid()?.is_some() {
+            if waited == 100 {
+                Command::new("kill")
+                    .arg(pid.to_string())
+                    .status()
+                    .and_then(status_error)
+                    .context("unable to kill qemu")?;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+            waited += 1;
+        }
+    } else {
+        eprintln!("redoxer: vm is not running");
+    }
+
+    let vm_dir = vm_dir();
+    if vm_dir.is_dir() {
+        fs::remove_dir_all(&vm_dir).context("unable to remove vm dir")?;
+    }
+    Ok(())
+}
+
+/// files of the folders copied into the vm, in the order they are copied
+fn folders_manifest(config: &RedoxerExecConfig) -> io::Result<String> {
+    let mut copies: Vec<(String, String)> = config
+        .folders
+        .iter()
+        .map(|(sysroot, folder)| (folder.clone(), sysroot.clone()))
+        .collect();
+    copies.sort();
+    manifest(&copies, &config.filter)
+}
+
+/// Run the command inside the running vm, returns None if the vm can't be used
+pub(crate) fn exec(config: &RedoxerExecConfig) -> anyhow::Result<Option<RunStatus>> {
+    if parse_bool_env("REDOXER_VM") == Some(false) || running_pid()?.is_none() {
+        return Ok(None);
+    }
+
+    let key = fs::read_to_string(vm_dir().join("config")).unwrap_or_default();
+    if key != vm_key(config) {
+        progress!("redoxer: vm was started with different folders, booting a new one");
+        return Ok(None);
+    }
+    if !config.artifacts.is_empty()
+        || config.stdin
+        || config.split_output
+        || config.gdb.is_some()
+        || !config.reports.is_empty()
+        || config.repeat.is_some()
+    {
+        progress!(
+            "redoxer: vm does not support --artifact, --stdin, --split-output, --gdb, --report or --repeat, booting a new one"
+        );
+        return Ok(None);
+    }
+    // a file added, removed or modified since the vm copied the folders
+    let started_with = fs::read_to_string(vm_dir().join("manifest")).unwrap_or_default();
+    if folders_manifest(config)? != started_with {
+        progress!("redoxer: folders changed since the vm started, booting a new one");
+        return Ok(None);
+    }
+
+    let wrapper = ExecWrapper {
+        env: config.env.clone(),
+        ..Default::default()
+    };
+    let arguments = wrapper.command(guest_arguments(
+        &config.arguments,
+        config.folders.get("root").map(String::as_str),
+    )?);
+    // the agent reads the working directory, then the command
+    let cwd = config.cwd.as_deref().unwrap_or("/root");
+    let job = format!("{cwd}\n{}", job_line(&arguments)?);
+
+    let lock = JobLock::acquire().context("unable to lock vm")?;
+    let mut stream =
+        UnixStream::connect(vm_dir().join("serial.sock")).context("unable to connect to vm")?;
+    stream.write_all(job.as_bytes())?;
+
+    // like a run in a new vm, --output file gets a copy of what is shown
+    let mut stdout = io::stdout();
+    let mut output = match config.output.as_deref() {
+        Some("-") | None => None,
+        Some(output) => Some(fs::File::create(output)?),
+    };
+    let deadline = config
+        .timeout
+        .and_then(|timeout| time::Instant::now().checked_add(timeout));
+    let mut reader = BufReader::new(stream);
+    let mut started = false;
+    let mut line = Vec::new();
+    let status = loop {
+        line.clear();
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            // zero would disable the timeout
+            reader
+                .get_ref()
+                .set_read_timeout(Some(remaining.max(time::Duration::from_millis(1))))?;
+        }
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => bail!("vm serial closed while running the command"),
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                stdout.flush()?;
+                progress!();
+                progress!(
+                    "## redoxer (failure, timed out after {:?}) ##",
+                    config.timeout.unwrap_or_default()
+                );
+                // the command is still running inside the guest
+                drop(lock);
+                stop()?;
+                return Ok(Some(RunStatus::Timeout));
+            }
+            Err(err) => return Err(err.into()),
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if !started {
+            started = text == JOB_MARKER;
+            continue;
+        }
+        if let Some(status) = text
+            .strip_prefix(EXIT_MARKER)
+            .and_then(|s| s.strip_suffix(" ##"))
+        {
+            match status.parse::<i32>() {
+                Ok(status) => break status,
+                Err(_) => bail!("vm sent an invalid exit status {status:?}"),
+            }
+        }
+        if line.ends_with(b"\r\n") {
+            line.truncate(line.len() - 2);
+            line.push(b'\n');
+        }
+        stdout.write_all(&line)?;
+        if let Some(output) = &mut output {
+            output.write_all(&line)?;
+        }
+    };
+    stdout.flush()?;
+
+    progress!();
+    if status == 0 {
+        progress!("## redoxer (success) ##");
+    } else {
+        progress!("## redoxer (failure, exit status {status}) ##");
+    }
+    Ok(Some(RunStatus::Guest(status)))
+}
+
+fn usage() -> ! {
+    eprintln!("redoxer vm start [exec options]... - boot a vm reused by redoxer exec");
+    eprintln!("redoxer vm status - show the running vm");
+    eprintln!("redoxer vm stop - shut down the running vm");
+    process::exit(1);
+}
+
+pub fn main(args: &[String]) {
+    let res = match args.get(2).map(String::as_str) {
+        Some("start") => RedoxerExecConfig::new(args.iter().skip(3).cloned())
+            .context("Unable to parse exec configuration")
+            .and_then(|mut config| {
+                add_project_rules(&mut config)?;
+                start(&config)
+            }),
+        Some("status") => status(),
+        Some("stop") => stop(),
+        _ => usage(),
+    };
+
+    match res {
+        Ok(()) => {
+            process::exit(0);
+        }
+        Err(err) => {
+            eprintln!("redoxer vm: {err:#}");
+            process::exit(1);
+        }
+    }
+}
@@ -6,15 +6,13 @@ use std::{
 
 static INIT_ENV: &str = include_str!("../res/run_redoxer.ion");
 static INIT_REDOXER: &str = include_str!("../res/30_redoxer");
+#[cfg(feature = "cli-exec")]
+static INIT_AGENT: &str = include_str!("../res/redoxer_agent.ion");
 
-pub fn write_redoxerd_config(
-    dest_dir: &Path,
-    arguments: &[String],
-    root_dir: Option<&str>,
-) -> Result<(), io::Error> {
-    let mut redoxerd_config = String::new();
+/// Replace absolute path to folder with /root in arguments
+pub fn guest_arguments(arguments: &[String], root_dir: Option<&str>) -> io::Result<Vec<String>> {
+    let mut guest_arguments = Vec::with_capacity(arguments.len());
     for arg in arguments.iter() {
-        // Replace absolute path to folder with /root in command name
         // TODO: make this activated by a flag
         if let Some(ref folder) = root_dir {
             let folder_canonical_path = fs::canonicalize(folder)?;
@@ -23,14 +21,116 @@ pub fn write_redoxerd_config(
                 .ok_or(io::Error::other("folder path is not valid UTF-8"))?;
             if arg.starts_with(folder_canonical) {
                 let arg_replace = arg.replace(folder_canonical, "/root");
-                eprintln!("redoxer: replacing '{arg}' with '{arg_replace}' in arguments");
-                redoxerd_config.push_str(&arg_replace);
-                redoxerd_config.push('\n');
+                progress!("redoxer: replacing '{arg}' with '{arg_replace}' in arguments");
+                guest_arguments.push(arg_replace);
                 continue;
             }
         }
 
-        redoxerd_config.push_str(arg);
+        guest_arguments.push(arg.clone());
+    }
+    Ok(guest_arguments)
+}
+
+/// printed before each run of the command with --repeat
+pub const REPEAT_MARKER: &[u8] = b"## redoxer repeat ";
+
+/// How the redoxer_exec.ion wrapper runs the command inside redoxerd
+#[derive(Clone, Default)]
+pub struct ExecWrapper {
+    // Content fed to the command as stdin
+    pub stdin: Option<Vec<u8>>,
+    // Report stdout and stderr of the command separately
+    pub split_output: bool,
+    // Environment variables set for the command
+    pub env: Vec<(String, String)>,
+    // Working directory of the command, instead of /root
+    pub cwd: Option<String>,
+    // Run the command this many times, stopping at the first failure
+    pub repeat: Option<u32>,
+}
+
+impl ExecWrapper {
+    /// prefix the command with `env` when environment variables are set
+    pub fn command(&self, arguments: Vec<String>) -> Vec<String> {
+        if self.env.is_empty() {
+            return arguments;
+        }
+        let mut command = vec!["env".to_string()];
+        command.extend(self.env.iter().map(|(k, v)| format!("{k}={v}")));
+        command.extend(arguments);
+        command
+    }
+
+    fn script(&self) -> String {
+        let runs = self.repeat.unwrap_or(1);
+        let mut command = String::new();
+        for run in 1..=runs {
+            if run > 1 {
+                command.push_str(" && ");
+            }
+            if self.repeat.is_some() {
+                let marker = String::from_utf8_lossy(REPEAT_MARKER);
+                command.push_str(&format!("echo \"{marker}{run}/{runs} ##\" && "));
+            }
+            command.push_str("@args[1..]");
+            if self.stdin.is_some() {
+                command.push_str(" < /etc/redoxerd.stdin");
+            }
+            if self.split_output {
+                // later runs append to the output of the first one
+                let append = if run > 1 { ">>" } else { ">" };
+                command.push_str(&format!(
+                    " {append} /var/tmp/redoxer.stdout ^{append} /var/tmp/redoxer.stderr"
+                ));
+            }
+        }
+
+        let mut script = String::from("#!/usr/bin/env ion\n");
+        if let Some(cwd) = &self.cwd {
+            // running in another directory could touch the wrong files
+            script.push_str(&format!("if not cd '{cwd}'\n"));
+            script.push_str(&format!(
+                "    echo \"redoxer: unable to change directory to '{cwd}'\"\n"
+            ));
+            script.push_str("    echo \"## redoxer exit status 1 ##\"\n");
+            script.push_str("    exit 1\n");
+            script.push_str("end\n");
+        }
+        script.push_str(&command);
+        script.push('\n');
+        // the host reads the exit status of the command from the log
+        script.push_str("let status = $?\n");
+        if self.split_output {
+            // base64 survives the newline translation of the pty
+            script.push_str("echo \"## redoxer stdout ##\"\n");
+            script.push_str("base64 /var/tmp/redoxer.stdout\n");
+            script.push_str("echo \"## redoxer stderr ##\"\n");
+            script.push_str("base64 /var/tmp/redoxer.stderr\n");
+            script.push_str("echo \"## redoxer end ##\"\n");
+        }
+        script.push_str("echo \"## redoxer exit status $status ##\"\n");
+        script.push_str("exit $status\n");
+        script
+    }
+}
+
+/// Write the command run by redoxerd, through the redoxer_exec.ion wrapper
+/// when given, or run directly like `redoxer write-exec` does
+pub fn write_redoxerd_config(
+    dest_dir: &Path,
+    arguments: &[String],
+    root_dir: Option<&str>,
+    wrapper: Option<&ExecWrapper>,
+) -> Result<(), io::Error> {
+    let mut guest_arguments = guest_arguments(arguments, root_dir)?;
+    let mut redoxerd_config = String::new();
+    if let Some(wrapper) = wrapper {
+        redoxerd_config.push_str("ion\n/usr/lib/redoxer_exec.ion\n");
+        guest_arguments = wrapper.command(guest_arguments);
+    }
+    for arg in guest_arguments {
+        redoxerd_config.push_str(&arg);
         redoxerd_config.push('\n');
     }
     let etc_dir = dest_dir.join("etc");
@@ -38,6 +138,9 @@ pub fn write_redoxerd_config(
         fs::create_dir_all(&etc_dir)?;
     }
     fs::write(etc_dir.join("redoxerd"), redoxerd_config)?;
+    if let Some(stdin) = wrapper.and_then(|wrapper| wrapper.stdin.as_ref()) {
+        fs::write(etc_dir.join("redoxerd.stdin"), stdin)?;
+    }
     let init_dir = dest_dir.join("usr/lib/init.d");
     let lib_dir = dest_dir.join("usr/lib");
     if !init_dir.is_dir() {
@@ -47,6 +150,9 @@ pub fn write_redoxerd_config(
     if !init_env_path.is_file() {
         fs::write(&init_env_path, INIT_ENV)?;
     }
+    if let Some(wrapper) = wrapper {
+        fs::write(lib_dir.join("redoxer_exec.ion"), wrapper.script())?;
+    }
     let init_redoxer_path = init_dir.join("30_redoxer");
     if !init_redoxer_path.is_file() {
         fs::write(&init_redoxer_path, INIT_REDOXER)?;
@@ -59,6 +165,17 @@ pub fn write_redoxerd_config(
     Ok(())
 }
 
+/// Replace the redoxerd init script with the agent used by `redoxer vm`
+#[cfg(feature = "cli-exec")]
+pub fn write_redoxer_agent(dest_dir: &Path) -> Result<(), io::Error> {
+    fs::write(dest_dir.join("usr/lib/redoxer_agent.ion"), INIT_AGENT)?;
+    fs::write(
+        dest_dir.join("usr/lib/init.d/30_redoxer"),
+        "requires_weak 10_net.target\nion /usr/lib/redoxer_agent.ion\n",
+    )?;
+    Ok(())
+}
+
 #[derive(Clone, Default)]
 struct RedoxerConfig {
     // Root path
@@ -107,7 +224,12 @@ impl RedoxerConfig {
 pub fn main(args: &[String]) {
     let config = RedoxerConfig::new(args.iter().skip(2).cloned());
 
-    match write_redoxerd_config(&config.root, &config.arguments, config.folder.as_deref()) {
+    match write_redoxerd_config(
+        &config.root,
+        &config.arguments,
+        config.folder.as_deref(),
+        None,
+    ) {
         Ok(()) => {
             process::exit(0);
         }